@@ -14,6 +14,12 @@ func _on_body_entered(body):
 	if body.get("resource_name") != "Mass":
 		return
 	
+	# The Space handles the collisions itself when it integrates the masses,
+	# unless it ignores them (0).
+	var space = get_parent()
+	if space.get("collisions") and not space.get("godot_integration"):
+		return
+	
 	# The body with the lower mass is removed.
 	# If they have the same mass, the RID is used.
 	# RID is used to remove only one of the masses
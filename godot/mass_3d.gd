@@ -0,0 +1,6 @@
+class_name Mass3D
+
+extends RigidBody3D
+
+# Relevant to be processed by the Space3D node for the gravity calculation.
+var resource_name = "Mass"
@@ -0,0 +1,168 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use godot_gravity_simulator_rust::base::{Object, Softening, Vec2};
+use godot_gravity_simulator_rust::collision;
+use godot_gravity_simulator_rust::barnes_hut::{Mac, Quadtree, THETA};
+use rayon::ThreadPoolBuilder;
+
+mod common;
+use common::objects;
+
+/// The masses of the some_large_many_small_masses scene.
+/// Masses without a position or mass in the scene have the defaults of a RigidBody2D.
+fn scene_objects() -> Vec<Object> {
+    let scene = include_str!("../../godot/some_large_many_small_masses.tscn");
+
+    scene.split("[node ")
+        .filter(|node| node.contains("instance="))
+        .map(|node| {
+            let mut object = Object::new(0., 0., 1.);
+            for line in node.lines() {
+                if let Some(position) = line.strip_prefix("position = Vector2(") {
+                    let (x, y) = position.trim_end_matches(')').split_once(", ").unwrap();
+                    object.x = x.parse().unwrap();
+                    object.y = y.parse().unwrap();
+                } else if let Some(m) = line.strip_prefix("mass = ") {
+                    object.m = m.parse().unwrap();
+                }
+            }
+            object
+        })
+        .collect()
+}
+
+fn build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("quadtree build");
+    for n in [1_000, 10_000, 100_000] {
+        let objects = objects(n);
+        let cell = Quadtree::calc_tree_box(&objects);
+
+        group.bench_with_input(BenchmarkId::from_parameter(n), &objects, |b, objects| {
+            b.iter(|| Quadtree::create_from_objects(black_box(objects), cell))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("quadtree morton build");
+    for n in [1_000, 10_000, 100_000] {
+        let objects = objects(n);
+        let cell = Quadtree::calc_tree_box(&objects);
+
+        group.bench_with_input(BenchmarkId::from_parameter(n), &objects, |b, objects| {
+            b.iter(|| Quadtree::create_from_objects_morton(black_box(objects), cell))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("quadtree rebuild");
+    for n in [1_000, 10_000, 100_000] {
+        let objects = objects(n);
+        let mut qtree = Quadtree::create_from_objects(&objects, Quadtree::calc_tree_box(&objects));
+
+        // The arena is reused, like the solver does between frames.
+        group.bench_with_input(BenchmarkId::from_parameter(n), &objects, |b, objects| {
+            b.iter(|| qtree.rebuild(black_box(objects)))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("quadtree refit");
+    for n in [1_000, 10_000, 100_000] {
+        let objects = objects(n);
+        // The objects move a little, like they do between two frames.
+        let moved: Vec<Object> = objects.iter().map(|object| Object::new(object.x * 0.9999 + 0.05, object.y * 0.9999 + 0.05, object.m)).collect();
+        let qtree = Quadtree::create_from_objects_morton(&objects, Quadtree::calc_tree_box(&objects));
+
+        group.bench_with_input(BenchmarkId::from_parameter(n), &moved, |b, moved| {
+            b.iter_batched(|| qtree.clone(), |mut qtree| qtree.refit(black_box(moved)), BatchSize::LargeInput)
+        });
+    }
+    group.finish();
+}
+
+fn parallel_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("quadtree parallel build");
+    let objects = objects(100_000);
+    let cell = Quadtree::calc_tree_box(&objects);
+
+    for threads in [1, 2, 4, 8] {
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &objects, |b, objects| {
+            b.iter(|| Quadtree::create_from_objects_parallel(black_box(objects), cell, &pool))
+        });
+    }
+    group.finish();
+}
+
+fn traversal(c: &mut Criterion) {
+    let mut group = c.benchmark_group("quadtree traversal");
+    for n in [1_000, 10_000] {
+        let objects = objects(n);
+        let qtree = Quadtree::create_from_objects(&objects, Quadtree::calc_tree_box(&objects));
+
+        group.bench_with_input(BenchmarkId::from_parameter(n), &objects, |b, objects| {
+            b.iter(|| qtree.calc_forces(black_box(objects), Mac::Classic(THETA), Softening::default()))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("quadtree morton traversal");
+    for n in [1_000, 10_000] {
+        let objects = objects(n);
+        let qtree = Quadtree::create_from_objects_morton(&objects, Quadtree::calc_tree_box(&objects));
+
+        group.bench_with_input(BenchmarkId::from_parameter(n), &objects, |b, objects| {
+            b.iter(|| qtree.calc_forces_morton(black_box(objects), Mac::Classic(THETA), Softening::default()))
+        });
+    }
+    group.finish();
+}
+
+fn parallel_traversal(c: &mut Criterion) {
+    let mut group = c.benchmark_group("quadtree parallel traversal");
+    let objects = objects(10_000);
+    let qtree = Quadtree::create_from_objects(&objects, Quadtree::calc_tree_box(&objects));
+
+    for threads in [1, 2, 4, 8] {
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &objects, |b, objects| {
+            b.iter(|| qtree.calc_forces_parallel(black_box(objects), Mac::Classic(THETA), Softening::default(), &pool))
+        });
+    }
+    group.finish();
+}
+
+fn grouped_traversal(c: &mut Criterion) {
+    // The scene uses θ = 1.5.
+    let scene = scene_objects();
+    let inputs = [("scene", scene, 1.5), ("10000", objects(10_000), THETA)];
+
+    let mut group = c.benchmark_group("quadtree grouped traversal");
+    for (name, objects, theta) in &inputs {
+        let qtree = Quadtree::create_from_objects_morton(objects, Quadtree::calc_tree_box(objects));
+
+        group.bench_with_input(BenchmarkId::new("per object", name), objects, |b, objects| {
+            b.iter(|| qtree.calc_forces_morton(black_box(objects), Mac::Classic(*theta), Softening::default()))
+        });
+        group.bench_with_input(BenchmarkId::new("per leaf", name), objects, |b, objects| {
+            b.iter(|| qtree.calc_forces_grouped(black_box(objects), Mac::Classic(*theta), Softening::default()))
+        });
+    }
+    group.finish();
+}
+
+fn overlapping_pairs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("overlapping pairs");
+    let objects = objects(10_000);
+    let positions: Vec<Vec2> = objects.iter().map(|object| object.pos()).collect();
+    let radii = vec![2.; objects.len()];
+    let qtree = Quadtree::create_from_objects_morton(&objects, Quadtree::calc_tree_box(&objects));
+
+    group.bench_function("all pairs", |b| b.iter(|| collision::overlapping_pairs(black_box(&positions), &radii)));
+    group.bench_function("quadtree", |b| b.iter(|| qtree.overlapping_pairs(black_box(&objects), &radii)));
+    group.finish();
+}
+
+criterion_group!(benches, build, parallel_build, traversal, parallel_traversal, grouped_traversal, overlapping_pairs);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+use godot_gravity_simulator_rust::base::{Object, Scalar};
+
+/// Deterministic pseudo random objects, so the benchmarks are comparable between runs.
+pub fn objects(n: usize) -> Vec<Object> {
+    let mut seed: u64 = 0x9E3779B97F4A7C15;
+    let mut next = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % 1_000_000) as Scalar / 1_000.
+    };
+    (0..n).map(|_| Object::new(next(), next(), 1. + next())).collect()
+}
@@ -0,0 +1,31 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use godot_gravity_simulator_rust::base::{Softening, SofteningKernel};
+use godot_gravity_simulator_rust::direct;
+
+mod common;
+use common::objects;
+
+fn direct(c: &mut Criterion) {
+    // Direct::calc_forces uses the symmetric or the tiled one, the vectorized one only if Direct::simd is set.
+    for (kernel, n) in [SofteningKernel::None, SofteningKernel::Plummer, SofteningKernel::Spline].into_iter()
+        .flat_map(|kernel| [(kernel, 1_000), (kernel, 4_000)])
+    {
+        let softening = Softening::new(kernel, 1.);
+        let objects = objects(n);
+        let mut group = c.benchmark_group(format!("direct {kernel:?} {n}"));
+
+        group.bench_with_input(BenchmarkId::from_parameter("symmetric"), &objects, |b, objects| {
+            b.iter(|| direct::get_forces(black_box(objects), softening))
+        });
+        group.bench_with_input(BenchmarkId::from_parameter("tiled"), &objects, |b, objects| {
+            b.iter(|| direct::get_forces_tiled(black_box(objects), softening))
+        });
+        group.bench_with_input(BenchmarkId::from_parameter("simd"), &objects, |b, objects| {
+            b.iter(|| direct::get_forces_simd(black_box(objects), softening))
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, direct);
+criterion_main!(benches);
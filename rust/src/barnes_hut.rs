@@ -1,11 +1,262 @@
 pub mod quadtree;
+pub mod octree;
+pub mod morton;
 
-pub use quadtree::Quadtree;
+pub use quadtree::{Quadtree, BUCKET_SIZE, MAX_DEPTH, PADDING};
+pub use octree::Octree;
 
-pub use crate::base::{Vec2, Object, Scalar};
+pub use crate::base::{Vec2, Vec3, Object, Object3D, Scalar, Softening};
+use crate::solver::{GravitySolver, GravitySolver3D};
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::sync::Arc;
 
 pub const THETA: Scalar = 0.5;
 
+/// The relative force error α of AcceptanceCriterion::RelativeForce.
+pub const ALPHA: Scalar = 0.005;
+
+/// The quadtree is rebuilt for every force calculation by default, see BarnesHut::rebuild_interval.
+pub const REBUILD_INTERVAL: usize = 1;
+
+/// The forces are calculated with one walk through the quadtree per leaf by default, see BarnesHut::grouped.
+pub const GROUPED: bool = true;
+
+/// Decides if a node is far enough away from an object to be used as a whole (multipole acceptance criterion).
+/// With the godot feature it can be exported as a property.
+#[cfg_attr(feature = "godot", derive(godot::prelude::Property, godot::prelude::Export))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(i64)]
+pub enum AcceptanceCriterion {
+    /// size / distance < θ, with the distance to the center of mass.
+    #[default]
+    Classic = 0,
+    /// b_max / distance < θ, where b_max is the largest distance of the center of mass
+    /// to a corner of the cell (Salmon and Warren).
+    /// Unlike the classic criterion, it does not fail if the center of mass is close to a corner.
+    BMax = 1,
+    /// size / distance < θ, with the distance to the closest point of the cell.
+    MinDistance = 2,
+    /// The estimated force error of a node must be smaller than α times the acceleration
+    /// of the object in the previous step (Gadget). The classic criterion with θ is used for the first step.
+    RelativeForce = 3,
+}
+
+/// An acceptance criterion with its parameters, see AcceptanceCriterion.
+#[derive(Debug, Clone, Copy)]
+pub enum Mac<'a> {
+    Classic(Scalar),
+    BMax(Scalar),
+    MinDistance(Scalar),
+    /// α, the θ of the classic criterion that is used without a previous acceleration
+    /// and the accelerations of the objects in the previous step.
+    RelativeForce(Scalar, Scalar, &'a [Vec2]),
+}
+
+/// The moments of the nodes that are used for the force calculation.
+/// With the godot feature it can be exported as a property.
+#[cfg_attr(feature = "godot", derive(godot::prelude::Property, godot::prelude::Export))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(i64)]
+pub enum Multipoles {
+    /// Only the mass and the center of mass.
+    #[default]
+    Monopole = 0,
+    /// Also the quadrupole moments, which allow a larger θ for the same accuracy.
+    Quadrupole = 1,
+}
+
+/// The Barnes-Hut-Algorithm with O(n log(n)),
+/// which approximates distant groups of objects by their center of mass.
+#[derive(Debug, Clone)]
+pub struct BarnesHut {
+    /// The accuracy θ, see Quadtree::calc_force.
+    pub theta: Scalar,
+    /// Spreads the force calculation over a thread pool.
+    pub parallel: bool,
+    /// Walks the quadtree once per leaf for all of its objects, see Quadtree::calc_forces_grouped.
+    pub grouped: bool,
+    /// The number of threads of the pool, 0 uses one thread per core.
+    pub threads: usize,
+    pub multipoles: Multipoles,
+    pub criterion: AcceptanceCriterion,
+    /// The relative force error α of AcceptanceCriterion::RelativeForce.
+    pub alpha: Scalar,
+    pub softening: Softening,
+    /// The quadtree is only rebuilt every rebuild_interval force calculations
+    /// and refit to the moved objects in between, see Quadtree::refit.
+    /// With 0 or 1 it is rebuilt every time.
+    pub rebuild_interval: usize,
+    /// The number of objects a leaf holds, see Quadtree::bucket_size.
+    pub bucket_size: usize,
+    /// Bounds the depth of the quadtree, see Quadtree::max_depth.
+    pub max_depth: usize,
+    /// The space around the objects the cell of the quadtree has, see Quadtree::padding.
+    pub padding: Scalar,
+    /// The number of force calculations since the quadtree was rebuilt.
+    refits: usize,
+    /// The serial build keeps its memory.
+    qtree: Quadtree,
+    /// Is kept between frames and only created again when the number of threads changes.
+    pool: Option<Arc<ThreadPool>>,
+    /// The accelerations of the last force calculation, which AcceptanceCriterion::RelativeForce needs.
+    accelerations: Vec<Vec2>,
+}
+
+impl BarnesHut {
+    pub fn new(theta: Scalar, parallel: bool) -> Self {
+        Self {
+            theta,
+            parallel,
+            grouped: GROUPED,
+            threads: 0,
+            multipoles: Multipoles::default(),
+            criterion: AcceptanceCriterion::default(),
+            alpha: ALPHA,
+            softening: Softening::default(),
+            rebuild_interval: REBUILD_INTERVAL,
+            bucket_size: BUCKET_SIZE,
+            max_depth: MAX_DEPTH,
+            padding: PADDING,
+            refits: 0,
+            qtree: Quadtree::new(Cell::new(0., 0., 0.)),
+            pool: None,
+            accelerations: Vec::new(),
+        }
+    }
+
+    /// Returns the acceptance criterion with its parameters.
+    fn mac(&self) -> Mac<'_> {
+        match self.criterion {
+            AcceptanceCriterion::Classic => Mac::Classic(self.theta),
+            AcceptanceCriterion::BMax => Mac::BMax(self.theta),
+            AcceptanceCriterion::MinDistance => Mac::MinDistance(self.theta),
+            AcceptanceCriterion::RelativeForce => Mac::RelativeForce(self.alpha, self.theta, &self.accelerations),
+        }
+    }
+
+    /// Forgets the accelerations of the last force calculation.
+    /// Needs to be called when objects were added or removed, because they are stored by index.
+    /// Until the next force calculation AcceptanceCriterion::RelativeForce falls back to θ.
+    pub fn reset(&mut self) {
+        self.accelerations.clear();
+    }
+
+    /// Stores the accelerations of the objects for the next force calculation.
+    fn store_accelerations(&mut self, objects: &[Object], targets: impl Iterator<Item = usize>, forces: &[Vec2]) {
+        if self.criterion != AcceptanceCriterion::RelativeForce { return }
+
+        self.accelerations.resize(objects.len(), Vec2::new(0., 0.));
+        for (i, force) in targets.zip(forces) {
+            let m = objects[i].m;
+            self.accelerations[i] = if m == 0. { Vec2::new(0., 0.) } else { *force / m };
+        }
+    }
+
+    /// Refits the quadtree to the objects or rebuilds it, if it is time to or refitting fails.
+    /// The parallel build is used if a pool is provided.
+    fn update_quadtree(&mut self, objects: &[Object], pool: Option<&ThreadPool>) {
+        self.refits += 1;
+        if self.refits < self.rebuild_interval && self.qtree.refit(objects) { return }
+
+        self.rebuild_quadtree(objects, pool);
+    }
+
+    /// Returns the quadtree at the current positions of the objects, for example for collision detection.
+    /// It is refit or rebuilt like for a force calculation, so the refits count towards the rebuild_interval.
+    pub fn quadtree(&mut self, objects: &[Object]) -> &Quadtree {
+        self.update_quadtree(objects, None);
+        &self.qtree
+    }
+
+    /// Is used by update_quadtree and quadtree.
+    fn rebuild_quadtree(&mut self, objects: &[Object], pool: Option<&ThreadPool>) {
+        self.refits = 0;
+        self.qtree.bucket_size = self.bucket_size;
+        self.qtree.max_depth = self.max_depth;
+        self.qtree.padding = self.padding;
+        match pool {
+            Some(pool) => self.qtree.rebuild_parallel(objects, pool),
+            None => self.qtree.rebuild_morton(objects),
+        }
+    }
+
+    /// Calculates the moments the quadtree needs in addition to the centers of mass.
+    fn calc_multipoles(&mut self) {
+        if self.multipoles == Multipoles::Quadrupole {
+            self.qtree.calc_quadrupoles();
+        }
+    }
+
+    /// Returns the thread pool with the configured number of threads.
+    fn thread_pool(&mut self) -> Arc<ThreadPool> {
+        let threads = if self.threads == 0 { rayon::current_num_threads() } else { self.threads };
+
+        match &self.pool {
+            Some(pool) if pool.current_num_threads() == threads => pool.clone(),
+            _ => {
+                let pool = Arc::new(ThreadPoolBuilder::new().num_threads(threads).build().unwrap());
+                self.pool = Some(pool.clone());
+                pool
+            }
+        }
+    }
+}
+
+impl Default for BarnesHut {
+    fn default() -> Self {
+        Self::new(THETA, false)
+    }
+}
+
+impl GravitySolver for BarnesHut {
+    fn calc_forces(&mut self, objects: &[Object]) -> Vec<Vec2> {
+        if objects.is_empty() { return Vec::new() }
+
+        let forces = if self.parallel {
+            let pool = self.thread_pool();
+            self.update_quadtree(objects, Some(&pool));
+            self.calc_multipoles();
+            if self.grouped {
+                self.qtree.calc_forces_grouped_parallel(objects, self.mac(), self.softening, &pool)
+            } else {
+                self.qtree.calc_forces_parallel(objects, self.mac(), self.softening, &pool)
+            }
+        } else {
+            self.update_quadtree(objects, None);
+            self.calc_multipoles();
+            if self.grouped {
+                self.qtree.calc_forces_grouped(objects, self.mac(), self.softening)
+            } else {
+                self.qtree.calc_forces_morton(objects, self.mac(), self.softening)
+            }
+        };
+        self.store_accelerations(objects, 0..objects.len(), &forces);
+        forces
+    }
+
+    fn calc_forces_on(&mut self, objects: &[Object], targets: &[usize]) -> Vec<Vec2> {
+        if objects.is_empty() { return Vec::new() }
+
+        self.update_quadtree(objects, None);
+        self.calc_multipoles();
+        let forces: Vec<Vec2> = targets.iter().map(|&i| self.qtree.calc_force_mac(objects[i], i, self.mac(), self.softening)).collect();
+        self.store_accelerations(objects, targets.iter().copied(), &forces);
+        forces
+    }
+}
+
+/// Builds a new Octree for every call, without the arena, buckets, refitting
+/// and parallelization of the 2D solver.
+impl GravitySolver3D for BarnesHut {
+    fn calc_forces_3d(&mut self, objects: &[Object3D]) -> Vec<Vec3> {
+        if objects.is_empty() { return Vec::new() }
+
+        let otree = Octree::create_from_objects(objects, Octree::calc_tree_box(objects));
+        objects.iter().enumerate().map(|(i, object)| otree.calc_force(*object, i, self.theta, self.softening)).collect()
+    }
+}
+
 /// Cells are just temporary.
 /// TODO They are utilised in the building process of the quadtree.
 #[derive(Debug, Clone, Copy)]
@@ -63,6 +314,47 @@ impl Cell {
             ---------")
         }
     }
+
+    /// Returns the distance of the point to the closest point of the cell,
+    /// which is zero if the point is inside the cell.
+    pub fn distance(&self, pos: Vec2) -> Scalar {
+        let dx = (self.x - pos.x).max(pos.x - (self.x + self.size)).max(0.);
+        let dy = (self.y - pos.y).max(pos.y - (self.y + self.size)).max(0.);
+        (dx * dx + dy * dy).sqrt()
+    }
+
+    /// Returns the cell doubled in size towards the point, until it contains the point.
+    /// Stops if the size is not finite anymore, because the point is not finite.
+    pub fn expanded_to(&self, pos: Vec2) -> Cell {
+        // A cell without a size can not be doubled.
+        let mut cell = if self.size > 0. { *self } else { Self::new(self.x, self.y, 1.) };
+
+        while !cell.contains(pos) && cell.size.is_finite() {
+            // The cell grows to the side of the point.
+            let x = if pos.x < cell.x { cell.x - cell.size } else { cell.x };
+            let y = if pos.y < cell.y { cell.y - cell.size } else { cell.y };
+            cell = Self::new(x, y, 2. * cell.size);
+        }
+        cell
+    }
+
+    /// Returns the distance between the closest points of the cells,
+    /// which is zero if they overlap.
+    pub fn distance_to_cell(&self, other: &Cell) -> Scalar {
+        let dx = (self.x - (other.x + other.size)).max(other.x - (self.x + self.size)).max(0.);
+        let dy = (self.y - (other.y + other.size)).max(other.y - (self.y + self.size)).max(0.);
+        (dx * dx + dy * dy).sqrt()
+    }
+
+    /// Checks if the cell and the rectangle from min to max overlap or touch.
+    pub fn intersects(&self, min: Vec2, max: Vec2) -> bool {
+        self.x <= max.x && min.x <= self.x + self.size && self.y <= max.y && min.y <= self.y + self.size
+    }
+
+    /// Checks if the point is inside the cell or on its boundary.
+    pub fn contains(&self, pos: Vec2) -> bool {
+        self.x <= pos.x && pos.x <= self.x + self.size && self.y <= pos.y && pos.y <= self.y + self.size
+    }
 }
 
 impl std::fmt::Display for Cell {
@@ -70,3 +362,92 @@ impl std::fmt::Display for Cell {
         write!(formatter, "Cell(x: {}, y: {}, size: {})", self.x, self.y, self.size)
     }
 }
+
+/// The 3D counterpart of Cell, a cube.
+#[derive(Debug, Clone, Copy)]
+pub struct Cell3D {
+    x: Scalar,
+    y: Scalar,
+    z: Scalar,
+    size: Scalar,
+}
+
+impl Cell3D {
+    pub fn new(x: Scalar, y: Scalar, z: Scalar, size: Scalar) -> Self {
+        Self { x, y, z, size }
+    }
+
+    /// Returns the center of the cell.
+    pub fn center(&self) -> Vec3 {
+        let half_size = self.size / 2.;
+        Vec3::new(self.x + half_size, self.y + half_size, self.z + half_size)
+    }
+
+    /// Returns the octant the point is in,
+    /// even if the point is not inside the cells boundary.
+    /// The octants are numbered like the quadrants (see Cell::quadrant),
+    /// with 4 added for the upper half in z.
+    pub fn octant(&self, x: Scalar, y: Scalar, z: Scalar) -> usize {
+        let center = self.center();
+        // True converted to a usize is 1.
+        let x_bit = (x >= center.x) as usize;
+        let y_bit = (y >= center.y) as usize;
+        let z_bit = (z >= center.z) as usize;
+        x_bit + (y_bit << 1) + (z_bit << 2)
+    }
+
+    /// Returns the suboctant of the cell based on the provided octant.
+    /// Panics if the octant is not smaller than 8.
+    pub fn child(&self, octant: usize) -> Cell3D {
+        assert!(octant < 8, "Octant has to be smaller than 8");
+
+        let half_size = self.size / 2.;
+        let offset = |bit: usize| if octant & bit != 0 { half_size } else { 0. };
+        Self::new(self.x + offset(1), self.y + offset(2), self.z + offset(4), half_size)
+    }
+}
+
+impl std::fmt::Display for Cell3D {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "Cell3D(x: {}, y: {}, z: {}, size: {})", self.x, self.y, self.z, self.size)
+    }
+}
+
+#[cfg(test)]
+mod barnes_hut_tests {
+    use crate::barnes_hut::{AcceptanceCriterion, BarnesHut};
+    use crate::solver::GravitySolver;
+    use crate::tests::objects;
+
+    #[test]
+    fn test_queries_count_towards_the_rebuild_interval() {
+        let objects = objects(100, 101., 97., |_| 1e10);
+        let mut barnes_hut = BarnesHut { rebuild_interval: 3, ..Default::default() };
+
+        barnes_hut.calc_forces(&objects);
+        barnes_hut.quadtree(&objects);
+        assert_eq!(barnes_hut.refits, 2);
+
+        // The third update is a rebuild, no matter if it is a query.
+        barnes_hut.quadtree(&objects);
+        assert_eq!(barnes_hut.refits, 0);
+        barnes_hut.calc_forces(&objects);
+        assert_eq!(barnes_hut.refits, 1);
+    }
+
+    #[test]
+    fn test_reset_forgets_the_accelerations() {
+        let objects = objects(100, 101., 97., |_| 1e10);
+        let mut barnes_hut = BarnesHut { criterion: AcceptanceCriterion::RelativeForce, ..Default::default() };
+
+        barnes_hut.calc_forces(&objects);
+        assert_eq!(barnes_hut.accelerations.len(), objects.len());
+
+        // Without the accelerations the forces are the ones of the classic criterion.
+        barnes_hut.reset();
+        assert!(barnes_hut.accelerations.is_empty());
+        let forces = barnes_hut.calc_forces(&objects);
+        let classic = BarnesHut::default().calc_forces(&objects);
+        assert_eq!(forces, classic);
+    }
+}
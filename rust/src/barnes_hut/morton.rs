@@ -0,0 +1,108 @@
+use crate::base::{Scalar, Object};
+use crate::barnes_hut::Cell;
+
+/// The number of levels a Morton key can describe.
+/// Every level uses one bit per axis.
+pub const MORTON_DEPTH: usize = 32;
+
+/// Returns the Morton key (the position on the Z-curve) of the point inside the cell.
+/// The two bits of a level are numbered like the quadrants (see Cell::quadrant),
+/// the first level is stored in the two highest bits.
+pub fn morton_key(x: Scalar, y: Scalar, cell: Cell) -> u64 {
+    // Every coordinate is quantised to 32 bits.
+    let quantise = |value: Scalar, origin: Scalar| {
+        if cell.size <= 0. { return 0 }
+        let value = ((value - origin) / cell.size) as f64 * (1u64 << MORTON_DEPTH) as f64;
+        value.clamp(0., u32::MAX as f64) as u64
+    };
+
+    spread_bits(quantise(x, cell.x)) | (spread_bits(quantise(y, cell.y)) << 1)
+}
+
+/// Returns the quadrant the key is in on the provided level (0 is the level of the root cells quadrants).
+pub fn quadrant(key: u64, level: usize) -> usize {
+    ((key >> (2 * (MORTON_DEPTH - 1 - level))) & 3) as usize
+}
+
+/// Inserts a zero bit between all of the lower 32 bits.
+fn spread_bits(value: u64) -> u64 {
+    let mut value = value & 0xFFFF_FFFF;
+    value = (value | (value << 16)) & 0x0000_FFFF_0000_FFFF;
+    value = (value | (value << 8)) & 0x00FF_00FF_00FF_00FF;
+    value = (value | (value << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    value = (value | (value << 2)) & 0x3333_3333_3333_3333;
+    value = (value | (value << 1)) & 0x5555_5555_5555_5555;
+    value
+}
+
+/// Returns the indices of the objects sorted by their Morton keys.
+pub fn morton_order(objects: &[Object], cell: Cell) -> (Vec<u64>, Vec<usize>) {
+    let keys: Vec<u64> = objects.iter().map(|object| morton_key(object.x, object.y, cell)).collect();
+    let order = radix_sort(&keys);
+    (order.iter().map(|&i| keys[i]).collect(), order)
+}
+
+/// Returns the indices that sort the keys, with a least significant digit radix sort in O(n).
+/// Keys that are equal keep their order.
+pub fn radix_sort(keys: &[u64]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..keys.len()).collect();
+    let mut buffer = vec![0; keys.len()];
+
+    // Sorts by one byte per pass, starting with the lowest.
+    for shift in (0..64).step_by(8) {
+        let digit = |i: usize| ((keys[i] >> shift) & 0xFF) as usize;
+
+        let mut counts = [0usize; 256];
+        for &i in &order {
+            counts[digit(i)] += 1;
+        }
+        // Every key is in the same bucket, so the pass would not change the order.
+        if counts.contains(&keys.len()) { continue }
+
+        // The first index of every bucket.
+        let mut starts = [0usize; 256];
+        for byte in 1..256 {
+            starts[byte] = starts[byte - 1] + counts[byte - 1];
+        }
+
+        for &i in &order {
+            buffer[starts[digit(i)]] = i;
+            starts[digit(i)] += 1;
+        }
+        std::mem::swap(&mut order, &mut buffer);
+    }
+    order
+}
+
+#[cfg(test)]
+mod morton_tests {
+    use crate::base::*;
+    use crate::barnes_hut::Cell;
+    use crate::barnes_hut::morton::*;
+
+    #[test]
+    fn test_radix_sort() {
+        let keys: Vec<u64> = (0..1000u64).map(|i| i.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ (i % 7)).collect();
+        let order = radix_sort(&keys);
+
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(order.iter().map(|&i| keys[i]).collect::<Vec<_>>(), sorted);
+    }
+
+    #[test]
+    fn test_key_quadrants_match_cells() {
+        let cell = Cell::new(-3., 2., 16.);
+
+        for i in (0..100).map(|i| i as Scalar) {
+            let (x, y) = (-3. + (i * 3.7) % 16., 2. + (i * 5.3) % 16.);
+            let key = morton_key(x, y, cell);
+
+            let mut current_cell = cell;
+            for level in 0..4 {
+                assert_eq!(quadrant(key, level), current_cell.quadrant(x, y));
+                current_cell = current_cell.child(current_cell.quadrant(x, y));
+            }
+        }
+    }
+}
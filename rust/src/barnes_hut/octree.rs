@@ -0,0 +1,246 @@
+use crate::base::{Scalar, Object3D, Vec3, Softening};
+use crate::barnes_hut::Cell3D;
+
+/// Bounds the depth of the octree, objects at (almost) the same position
+/// would otherwise be split up indefinitely.
+pub const MAX_DEPTH: usize = 64;
+
+/// The 3D counterpart of the Quadtree.
+/// The coordinates describe the center of mass for that cell.
+/// It is not optimized like the Quadtree: every node allocates its own children,
+/// a leaf holds a single object below MAX_DEPTH and the octree is built again for every force calculation.
+#[derive(Debug, Clone)]
+pub struct Octree {
+    x: Scalar,
+    y: Scalar,
+    z: Scalar,
+    m: Scalar,
+    children: Vec<Option<Self>>,
+    /// The objects a leaf holds with their index.
+    /// Only a leaf at MAX_DEPTH holds more than one.
+    bodies: Vec<(usize, Object3D)>,
+    cell: Cell3D,
+}
+
+impl Octree {
+    /// Creates an octree from a set of objects.
+    /// The cell configures the size of the octree.
+    pub fn create_from_objects(objects: &[Object3D], cell: Cell3D) -> Self {
+        let mut root = Self::leaf(cell.x, cell.y, cell.z, 0., cell);
+
+        for (i, object) in objects.iter().enumerate() {
+            root.insert(*object, i, cell);
+        }
+        root
+    }
+
+    /// Returns the smallest cube containing all objects, see Quadtree::calc_tree_box.
+    pub fn calc_tree_box(objects: &[Object3D]) -> Cell3D {
+        let Some(first) = objects.first() else { return Cell3D::new(0., 0., 0., 1.) };
+
+        let mut min = first.pos();
+        let mut max = first.pos();
+
+        for object in objects {
+            min.x = min.x.min(object.x); max.x = max.x.max(object.x);
+            min.y = min.y.min(object.y); max.y = max.y.max(object.y);
+            min.z = min.z.min(object.z); max.z = max.z.max(object.z);
+        }
+        let size = (max.x - min.x).max(max.y - min.y).max(max.z - min.z);
+
+        // If all objects are at the same position any size works.
+        let size = if size > 0. { size } else { 1. };
+        Cell3D::new(min.x, min.y, min.z, size)
+    }
+
+    /// Creates an empty octree node.
+    /// Because it is a leaf, it holds objects
+    /// or if m == 0 it is an empty octree.
+    pub fn leaf(x: Scalar, y: Scalar, z: Scalar, m: Scalar, cell: Cell3D) -> Self {
+        Self {
+            x,
+            y,
+            z,
+            m,
+            children: vec![None; 8],
+            bodies: Vec::new(),
+            cell,
+        }
+    }
+
+    /// Inserts the object with the index body into the octree, see Quadtree::insert.
+    /// The depth is bounded by MAX_DEPTH, a leaf at that depth holds all objects that fall into it.
+    pub fn insert(&mut self, object: Object3D, body: usize, cell: Cell3D) {
+        let Object3D { x, y, z, m } = object;
+
+        // An object with no mass is not influenced and does not influene gravity.
+        if m == 0. { return }
+
+        // If no object has been inserted before, the root node can just hold it.
+        if self.m == 0. {
+            self.x = x; self.y = y; self.z = z; self.m = m;
+            self.bodies.push((body, object));
+            return
+        }
+
+        // Find the parent node to insert the node under.
+        let mut current: &mut Self = self;
+        let mut current_cell: Cell3D = cell;
+        let mut octant: usize = cell.octant(x, y, z);
+        let mut depth = 0;
+
+        while current.children[octant].is_some() {
+            current.update_com(x, y, z, m);
+
+            current = current.children[octant].as_mut().unwrap();
+            current_cell = current_cell.child(octant);
+            depth += 1;
+
+            octant = current_cell.octant(x, y, z);
+        }
+
+        // Because a leaf holds a single object below MAX_DEPTH,
+        // the two objects must be split up into seperate cells.
+        if current.is_leaf() {
+            // Objects at (almost) the same position would otherwise be split up indefinitely.
+            if depth == MAX_DEPTH {
+                current.update_com(x, y, z, m);
+                current.bodies.push((body, object));
+                return
+            }
+
+            // The object that was held by the leaf (now referred to as object2).
+            let bodies = std::mem::take(&mut current.bodies);
+            let Object3D { x: x2, y: y2, z: z2, m: m2 } = bodies[0].1;
+            current.update_com(x, y, z, m);
+
+            let mut octant2: usize = current_cell.octant(x2, y2, z2);
+
+            // Splits the cell until the objects are not in the same octant anymore.
+            while octant == octant2 {
+                current.new_child(octant, current.x, current.y, current.z, current.m, current_cell.child(octant));
+
+                current = current.children[octant].as_mut().unwrap();
+                current_cell = current_cell.child(octant);
+                depth += 1;
+
+                // The new leaf holds both objects.
+                if depth == MAX_DEPTH {
+                    current.bodies = bodies;
+                    current.bodies.push((body, object));
+                    return
+                }
+
+                octant = current_cell.octant(x, y, z);
+                octant2 = current_cell.octant(x2, y2, z2);
+            }
+            current.new_child(octant2, x2, y2, z2, m2, current_cell.child(octant2));
+            current.children[octant2].as_mut().unwrap().bodies = bodies;
+        } else {
+            current.update_com(x, y, z, m);
+        }
+        current.new_child(octant, x, y, z, m, current_cell.child(octant));
+        current.children[octant].as_mut().unwrap().bodies.push((body, object));
+    }
+
+    /// Updates the center of mass.
+    pub fn update_com(&mut self, x: Scalar, y: Scalar, z: Scalar, m: Scalar) {
+        let total_m = self.m + m;
+        self.x = (self.m * self.x + m * x) / total_m;
+        self.y = (self.m * self.y + m * y) / total_m;
+        self.z = (self.m * self.z + m * z) / total_m;
+        self.m = total_m;
+    }
+
+    /// Checks if the node is a leaf.
+    pub fn is_leaf(&self) -> bool {
+        self.children.iter().all(|child| child.is_none())
+    }
+
+    /// Adds a child under the node.
+    pub fn new_child(&mut self, octant: usize, x: Scalar, y: Scalar, z: Scalar, m: Scalar, cell: Cell3D) {
+        self.children[octant] = Some(Self::leaf(x, y, z, m, cell))
+    }
+
+    /// Calculates the total force that acts on the object i
+    /// for the accuracy θ, with the same criterion as Quadtree::calc_force.
+    pub fn calc_force(&self, obj: Object3D, i: usize, theta: Scalar, softening: Softening) -> Vec3 {
+        let mut total_force = Vec3::new(0., 0., 0.);
+        let mut sub_trees = vec![self];
+
+        while let Some(sub_tree) = sub_trees.pop() {
+            // The objects of a leaf are used one by one, so the object itself can be skipped by its index.
+            if sub_tree.is_leaf() {
+                for &(j, obj2) in &sub_tree.bodies {
+                    if i != j { total_force += softening.force_3d(obj, obj2) }
+                }
+                continue;
+            }
+
+            let com = Vec3::new(sub_tree.x, sub_tree.y, sub_tree.z);
+            let distance = (com - obj.pos()).length();
+
+            if sub_tree.cell.size / distance < theta {
+                let obj2 = Object3D::new(sub_tree.x, sub_tree.y, sub_tree.z, sub_tree.m);
+                total_force += softening.force_3d(obj, obj2);
+                continue;
+            }
+
+            sub_trees.extend(sub_tree.children.iter().flatten());
+        }
+        total_force
+    }
+}
+
+#[cfg(test)]
+mod octree_tests {
+    use crate::base::*;
+    use crate::barnes_hut::Octree;
+    use crate::direct::get_forces_3d;
+    use crate::tests::objects_3d;
+
+    #[test]
+    fn test_exact_for_theta_zero() {
+        let objects = objects_3d(60, |i| 1e10 + i);
+        let exact = get_forces_3d(&objects, Softening::default());
+
+        let otree = Octree::create_from_objects(&objects, Octree::calc_tree_box(&objects));
+
+        for (i, (object, exact_force)) in objects.iter().zip(&exact).enumerate() {
+            let force = otree.calc_force(*object, i, 0., Softening::default());
+            assert!((force - *exact_force).length() <= 1e-3 * exact_force.length());
+        }
+    }
+
+    #[test]
+    fn test_coincident_objects() {
+        let mut objects = objects_3d(60, |i| 1e10 + i);
+        objects.push(objects[0]);
+        objects.push(Object3D::new(objects[0].x + 1e-6, objects[0].y, objects[0].z, 1e10));
+        objects.push(objects[5]);
+        objects.push(objects[5]);
+        let softening = Softening::new(SofteningKernel::Plummer, 1.);
+        let exact = get_forces_3d(&objects, softening);
+
+        let otree = Octree::create_from_objects(&objects, Octree::calc_tree_box(&objects));
+
+        // The objects that share a leaf at the maximum depth are kept apart,
+        // so every object gets the exact force without the force of itself.
+        for (i, (object, exact_force)) in objects.iter().zip(&exact).enumerate() {
+            let force = otree.calc_force(*object, i, 0., softening);
+            assert!((force - *exact_force).length() <= 1e-3 * exact_force.length());
+        }
+        assert_eq!(Octree::calc_tree_box(&[]).size, 1.);
+    }
+
+    #[test]
+    fn test_direct_forces_cancel_out() {
+        let total_force = get_forces_3d(&objects_3d(60, |i| 1e10 + i), Softening::default()).into_iter()
+            .fold(Vec3::new(0., 0., 0.), |total, force| total + force);
+        let largest_force = get_forces_3d(&objects_3d(60, |i| 1e10 + i), Softening::default()).into_iter()
+            .map(|force| force.length())
+            .fold(0., Scalar::max);
+
+        assert!(total_force.length() < 1e-3 * largest_force);
+    }
+}
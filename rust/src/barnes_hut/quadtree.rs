@@ -1,203 +1,956 @@
-use crate::base::{Scalar, Object, Vec2, G};
-use crate::barnes_hut::Cell;
+use crate::base::{Scalar, Object, Vec2, Softening, Particles, G};
+use crate::barnes_hut::{Cell, Mac};
+use crate::barnes_hut::morton::{self, MORTON_DEPTH};
+use crate::kernel;
+use crate::collision;
 
-/// The coordinates describe the center of mass for that cell.
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+/// Bounds the depth of the quadtree by default, see Quadtree::max_depth.
+pub const MAX_DEPTH: usize = 64;
+
+/// The number of objects a leaf holds by default, see Quadtree::bucket_size.
+pub const BUCKET_SIZE: usize = 8;
+
+/// The space around the objects the cell of the root has by default, see Quadtree::padding.
+pub const PADDING: Scalar = 0.;
+
+/// Below this number of objects a subtree is build by inserting the objects one at a time,
+/// because splitting them up onto threads would take longer.
+pub const PARALLEL_BUILD_CUTOFF: usize = 1024;
+
+/// Marks the end of the list of objects of a leaf
+/// and an object that was not inserted with its index.
+const NONE: usize = usize::MAX;
+
+/// The nodes of the quadtree are stored in one contiguous arena,
+/// the root is always the first node.
+/// The arena can be cleared and reused between frames without allocating again.
 #[derive(Debug, Clone)]
 pub struct Quadtree {
+    /// The number of objects a leaf holds before it is subdivided.
+    /// The forces of the objects of a leaf are calculated directly, if the leaf is too close to be used as a whole.
+    /// Changes take effect when the quadtree is build the next time.
+    pub bucket_size: usize,
+    /// Bounds the depth of the quadtree,
+    /// objects at (almost) the same position would otherwise be split up indefinitely.
+    /// The leaves at this depth hold all objects that fall into them, no matter the bucket_size.
+    /// Changes take effect when the quadtree is build the next time.
+    pub max_depth: usize,
+    /// The rebuilds enlarge the smallest square containing all objects by padding times its size on every side,
+    /// so the objects can move further before the root has to grow (see insert).
+    pub padding: Scalar,
+    nodes: Vec<Node>,
+    /// The objects the leaves hold, see Node::first.
+    items: Vec<Item>,
+    /// The indices of the objects in Morton order, see create_from_objects_morton.
+    /// It is empty if the quadtree was build differently.
+    order: Vec<usize>,
+    /// The quadrupole moments (xx, xy, yy) of the nodes around their center of mass,
+    /// see calc_quadrupoles. It is empty if they were not calculated.
+    quadrupoles: Vec<[Scalar; 3]>,
+}
+
+/// The coordinates describe the center of mass for that cell.
+#[derive(Debug, Clone, Copy)]
+struct Node {
     x: Scalar,
     y: Scalar,
     m: Scalar,
-    children: Vec<Option<Self>>,
     cell: Cell,
+    /// The index of the first of the four children, which are stored next to each other
+    /// in the order of their quadrants. It is zero for a leaf, because the root can never be a child.
+    children: usize,
+    /// The index of the first item of a leaf, the others are linked through Item::next.
+    first: usize,
+    /// The number of objects inside the cell.
+    count: usize,
+}
+
+/// An object that is held by a leaf.
+#[derive(Debug, Clone, Copy)]
+struct Item {
+    object: Object,
+    /// The index of the object, which refit needs. NONE if it was inserted without one.
+    body: usize,
+    /// The next item of the same leaf.
+    next: usize,
+}
+
+impl Node {
+    /// Creates an empty node.
+    /// Because it is a leaf, it holds objects
+    /// or if count == 0 it is an empty quadtree.
+    fn leaf(x: Scalar, y: Scalar, m: Scalar, cell: Cell) -> Self {
+        Self { x, y, m, cell, children: 0, first: NONE, count: 0 }
+    }
+
+    /// Checks if the node is a leaf.
+    fn is_leaf(&self) -> bool {
+        self.children == 0
+    }
+
+    /// Returns the node with the index of its children shifted by the offset
+    /// and the index of its first item shifted by the item_offset.
+    fn shifted(&self, offset: usize, item_offset: usize) -> Self {
+        if self.is_leaf() {
+            Self { first: if self.first == NONE { NONE } else { self.first + item_offset }, ..*self }
+        } else {
+            Self { children: self.children + offset, ..*self }
+        }
+    }
+
+    /// Returns the largest distance of the center of mass to a corner of the cell.
+    fn b_max(&self) -> Scalar {
+        let dx = (self.x - self.cell.x).max(self.cell.x + self.cell.size - self.x);
+        let dy = (self.y - self.cell.y).max(self.cell.y + self.cell.size - self.y);
+        (dx * dx + dy * dy).sqrt()
+    }
+
+    /// Updates the center of mass.
+    fn update_com(&mut self, x: Scalar, y: Scalar, m: Scalar) {
+        let total_m = self.m + m;
+        self.x = (self.m * self.x + m * x) / total_m;
+        self.y = (self.m * self.y + m * y) / total_m;
+        self.m = total_m;
+    }
+
+    /// Adds an object to the center of mass.
+    /// The first object is copied exactly, so a node that contains only one object
+    /// is at the exact same position and does not exert a force onto it.
+    /// Objects without a mass have no weight, they only move the center of mass of an empty node.
+    fn add(&mut self, x: Scalar, y: Scalar, m: Scalar) {
+        if self.m == 0. {
+            self.x = x;
+            self.y = y;
+            self.m = m;
+        } else if m != 0. {
+            self.update_com(x, y, m);
+        }
+    }
 }
 
 impl Quadtree {
+    /// Creates an empty quadtree with the default bucket_size and max_depth.
+    /// The cell configures the size of the quadtree.
+    pub fn new(cell: Cell) -> Self {
+        Self::with_limits(cell, BUCKET_SIZE, MAX_DEPTH)
+    }
+
+    /// Creates an empty quadtree with the provided bucket_size and max_depth.
+    /// The cell configures the size of the quadtree.
+    pub fn with_limits(cell: Cell, bucket_size: usize, max_depth: usize) -> Self {
+        Self {
+            bucket_size,
+            max_depth,
+            padding: PADDING,
+            nodes: vec![Node::leaf(cell.x, cell.y, 0., cell)],
+            items: Vec::new(),
+            order: Vec::new(),
+            quadrupoles: Vec::new(),
+        }
+    }
+
     /// Creates a quadtree from a set of objects.
     /// The cell configures the size of the quadtree.
-    pub fn create_from_objects(objects: &Vec<Object>, cell: Cell) -> Self {
-        let mut root = Self::leaf(cell.x, cell.y, 0., cell);
+    pub fn create_from_objects(objects: &[Object], cell: Cell) -> Self {
+        let mut qtree = Self::new(cell);
 
-        for object in objects {
-            root.insert(object.x, object.y, object.m, cell);
+        for (i, object) in objects.iter().enumerate() {
+            qtree.insert_below(*object, i, MAX_DEPTH);
         }
-        root
+        qtree
     }
 
-    /// Returns a cell that has the size and the origin a quadtree,
-    /// which is build with the same objects, should be based on.
-    /// TODO WARNING this is not the minimal bounding box.
-    pub fn calc_tree_box(objects: &Vec<Object>) -> Cell {
-        let mut smallest_x = objects[0].x;
-        let mut largest_x = objects[0].x;
+    /// Creates a quadtree from a set of objects on the threads of the pool.
+    /// The objects are split up into the quadrants of the cell, the subtrees of the quadrants
+    /// are build concurrently and merged afterwards.
+    /// The resulting quadtree has the same nodes as the one create_from_objects builds,
+    /// only the order of the nodes in the arena is different.
+    pub fn create_from_objects_parallel(objects: &[Object], cell: Cell, pool: &ThreadPool) -> Self {
+        let mut qtree = Self::new(cell);
+        qtree.build_parallel(objects, cell, pool);
+        qtree
+    }
 
-        let mut smallest_y = objects[0].y;
-        let mut largest_y = objects[0].y;
+    /// Rebuilds the quadtree like create_from_objects_parallel, but keeps the bucket_size and max_depth.
+    pub fn rebuild_parallel(&mut self, objects: &[Object], pool: &ThreadPool) {
+        if objects.is_empty() { return self.clear(Cell::new(0., 0., 0.)) }
 
-        for object in objects {
-            if object.x < smallest_x { smallest_x = object.x }
-            else if object.x > largest_x { largest_x = object.x }
+        self.build_parallel(objects, Self::calc_tree_box_padded(objects, self.padding), pool);
+    }
 
-            if object.y < smallest_y { smallest_y = object.y }
-            else if object.y > largest_y { largest_y = object.y }
-        }
-        let size = largest_x.max(largest_y) - smallest_x.min(smallest_y);
+    /// Is used by create_from_objects_parallel and rebuild_parallel.
+    fn build_parallel(&mut self, objects: &[Object], cell: Cell, pool: &ThreadPool) {
+        // The objects keep their index, so the leaves know which object they hold.
+        let objects: Vec<(usize, Object)> = objects.iter().copied().enumerate().collect();
+        let cell = objects.iter().fold(cell, |cell, (_, object)| cell.expanded_to(object.pos()));
 
-        return Cell::new(smallest_x, smallest_y, size)
+        let (bucket_size, max_depth, padding) = (self.bucket_size, self.max_depth, self.padding);
+        *self = pool.install(|| Self::build_subtree(&objects, cell, bucket_size, max_depth));
+        // The subtrees only know the depth that was left below their own root.
+        self.max_depth = max_depth;
+        self.padding = padding;
     }
 
+    /// Is used by build_parallel.
+    /// The max_depth is the depth the objects can still be split up to below the cell.
+    fn build_subtree(objects: &[(usize, Object)], cell: Cell, bucket_size: usize, max_depth: usize) -> Self {
+        // A cell with at most bucket_size objects is a leaf, so it must not be split up.
+        if objects.len() <= PARALLEL_BUILD_CUTOFF.max(bucket_size) || max_depth == 0 {
+            let mut qtree = Self::with_limits(cell, bucket_size, max_depth);
+            for &(i, object) in objects {
+                qtree.insert_below(object, i, max_depth);
+            }
+            return qtree
+        }
 
-    /// Creates an empty quadtree node.
-    /// Because it is a leaf, it represents an object
-    /// or if m == 0 an empty quadtree.
-    pub fn leaf(x: Scalar, y: Scalar, m: Scalar, cell: Cell) -> Self {
-        Self {
-            x,
-            y,
-            m,
-            children: vec![None; 4],
-            cell,
+        let mut quadrants: [Vec<(usize, Object)>; 4] = Default::default();
+        for &(i, object) in objects {
+            quadrants[cell.quadrant(object.x, object.y)].push((i, object));
         }
+
+        let build = |quadrant: usize| Self::build_subtree(&quadrants[quadrant], cell.child(quadrant), bucket_size, max_depth - 1);
+        let ((qtree0, qtree1), (qtree2, qtree3)) = rayon::join(
+            || rayon::join(|| build(0), || build(1)),
+            || rayon::join(|| build(2), || build(3)),
+        );
+
+        Self::merge(cell, [qtree0, qtree1, qtree2, qtree3])
     }
 
-    /// Inserts an object into the quadtree.
-    /// An object is represented by a leaf.
-    pub fn insert(&mut self, x: Scalar, y: Scalar, m: Scalar, cell: Cell) {
-        // An object with no mass is not influenced and does not influene gravity.
-        if m == 0. { return }
+    /// Creates a quadtree for the cell with the provided quadtrees of its quadrants as children.
+    fn merge(cell: Cell, children: [Self; 4]) -> Self {
+        let len = 5 + children.iter().map(|child| child.nodes.len() - 1).sum::<usize>();
+        let mut nodes = Vec::with_capacity(len);
+        let mut items = Vec::with_capacity(children.iter().map(|child| child.items.len()).sum());
 
-        // If no object has been inserted before, the root node can just represent it.
-        if self.m == 0. { self.x = x; self.y = y; self.m = m; return }
-        
-        // Find the parent node to insert the node under.
-        let mut current: &mut Self = self;
-        let mut current_cell: Cell = cell;
-        let mut quadrant: usize = cell.quadrant(x, y);
-        
-        while let Some(_) = &mut current.children[quadrant] {
-            current.update_com(x, y, m);
+        // The roots of the children are placed right after the new root.
+        nodes.push(Node { children: 1, ..Node::leaf(cell.x, cell.y, 0., cell) });
+        nodes.extend(children.iter().map(|child| child.nodes[0]));
 
-            current = current.children[quadrant].as_mut().unwrap();
-            current_cell = current_cell.child(quadrant);
+        for (quadrant, child) in children.iter().enumerate() {
+            // The other nodes and the items of the child are appended, so their indices are shifted.
+            let offset = nodes.len() - 1;
+            let item_offset = items.len();
 
-            quadrant = current_cell.quadrant(x, y);
+            nodes[1 + quadrant] = child.nodes[0].shifted(offset, item_offset);
+            nodes.extend(child.nodes[1..].iter().map(|node| node.shifted(offset, item_offset)));
+            items.extend(child.items.iter().map(|item| Item { next: if item.next == NONE { NONE } else { item.next + item_offset }, ..*item }));
+
+            let root = child.nodes[0];
+            nodes[0].add(root.x, root.y, root.m);
+            nodes[0].count += root.count;
         }
+        Self { nodes, items, ..Self::with_limits(cell, children[0].bucket_size, children[0].max_depth) }
+    }
 
-        // This is only needed if current.is_leaf() is true,
-        // because this must be done before current.update_com(x, y, m) is called, this is not inside the if statement.
-        // The object that was represented by the node (now referred to as object2).
-        let (x2, y2, m2) = (current.x, current.y, current.m);
+    /// Creates a quadtree from a set of objects, which are sorted along the Z-curve first.
+    /// The objects of every cell are next to each other in that order,
+    /// so the cells can be split up without walking down from the root for every object
+    /// and the nodes of close cells end up close to each other in the arena.
+    /// Objects closer than the size of the cell divided by 2^MORTON_DEPTH end up in the same leaf.
+    pub fn create_from_objects_morton(objects: &[Object], cell: Cell) -> Self {
+        let mut qtree = Self::new(cell);
+        qtree.build_morton(objects, cell);
+        qtree
+    }
 
-        current.update_com(x, y, m);
+    /// Rebuilds the quadtree like create_from_objects_morton in the already allocated memory.
+    pub fn rebuild_morton(&mut self, objects: &[Object]) {
+        if objects.is_empty() { return self.clear(Cell::new(0., 0., 0.)) }
 
-        // Because a leaf represents an object,
-        // the two objects must be split up into seperate cells.
-        if current.is_leaf() {
+        self.build_morton(objects, Self::calc_tree_box_padded(objects, self.padding));
+    }
 
-            // The quadrant of object2.
-            let mut quadrant2: usize = current_cell.quadrant(x2, y2);
+    /// Is used by create_from_objects_morton and rebuild_morton.
+    fn build_morton(&mut self, objects: &[Object], cell: Cell) {
+        // The keys of objects outside the cell would be clamped to its boundary.
+        let cell = objects.iter().fold(cell, |cell, object| cell.expanded_to(object.pos()));
+        self.clear(cell);
 
-            // Splits the cell until the objects are not in the same quadrant anymore.
-            while quadrant == quadrant2 {
+        let (keys, order) = morton::morton_order(objects, cell);
 
-                // Creates a cell that contains both objects.
-                // The center of mass stays the same and does not need to be updated.
-                current.new_child(quadrant, current.x, current.y, current.m, current_cell.child(quadrant));
+        if !order.is_empty() { self.build_sorted(0, &keys, objects, &order, 0) }
+        self.order = order;
+    }
 
-                current = current.children[quadrant].as_mut().unwrap();
-                current_cell = current_cell.child(quadrant);
+    /// Is used by rebuild_morton.
+    /// Turns the node into the subtree of the objects with the sorted keys, which are all inside its cell.
+    /// The bodies are the indices of the objects in the same order as the keys.
+    /// The centers of mass are calculated from the children up.
+    fn build_sorted(&mut self, index: usize, keys: &[u64], objects: &[Object], bodies: &[usize], level: usize) {
+        if bodies.len() <= self.bucket_size.max(1) || level == self.max_depth.min(MORTON_DEPTH) {
+            // The items of the leaf are stored in Morton order next to each other.
+            let first = self.items.len();
+            let node = &mut self.nodes[index];
+            *node = Node { first, count: bodies.len(), ..Node::leaf(0., 0., 0., node.cell) };
 
-                quadrant = current_cell.quadrant(x, y);
-                quadrant2 = current_cell.quadrant(x2, y2);
+            for (k, &body) in bodies.iter().enumerate() {
+                let object = objects[body];
+                let next = if k + 1 == bodies.len() { NONE } else { first + k + 1 };
+                self.items.push(Item { object, body, next });
+                node.add(object.x, object.y, object.m);
             }
-            // Once the quadrants are different, a node is created for each object.
-            // (The node for the other object is created further below)
-            current.new_child(quadrant2, x2, y2, m2, current_cell.child(quadrant2));
+            return
+        }
+
+        let cell = self.nodes[index].cell;
+        let children = self.nodes.len();
+        for quadrant in 0..4 {
+            let cell = cell.child(quadrant);
+            self.nodes.push(Node::leaf(cell.x, cell.y, 0., cell));
+        }
+        self.nodes[index].children = children;
+        self.nodes[index].count = bodies.len();
+
+        // The keys are sorted, so the objects of a quadrant are next to each other.
+        let mut start = 0;
+        for quadrant in 0..4 {
+            let end = start + keys[start..].partition_point(|&key| morton::quadrant(key, level) == quadrant);
+
+            if start < end {
+                self.build_sorted(children + quadrant, &keys[start..end], objects, &bodies[start..end], level + 1);
+
+                let child = self.nodes[children + quadrant];
+                self.nodes[index].add(child.x, child.y, child.m);
+            }
+            start = end;
         }
-        // If the node is not a leaf
-        // and the node does not contain a child in which the object would fit,
-        // a child, which represents the object, is created.
-        current.new_child(quadrant, x, y, m, current_cell.child(quadrant));
     }
 
-    /// Updates the center of mass.
-    pub fn update_com(&mut self, x: Scalar, y: Scalar, m: Scalar) {
-        let total_m = self.m + m;
-        self.x = (self.m * self.x + m * x) / total_m;
-        self.y = (self.m * self.y + m * y) / total_m;
-        self.m = total_m;
+    /// Calculates the total force for every object in the Morton order of the quadtree,
+    /// so consecutive objects walk through mostly the same nodes.
+    /// The forces are returned in the order of the objects.
+    /// Falls back to calc_forces if the quadtree was not build with create_from_objects_morton.
+    pub fn calc_forces_morton(&self, objects: &[Object], mac: Mac, softening: Softening) -> Vec<Vec2> {
+        if self.order.len() != objects.len() { return self.calc_forces(objects, mac, softening) }
+
+        let mut forces = vec![Vec2::new(0., 0.); objects.len()];
+        let mut bucket_particles = Particles::new();
+        for &i in &self.order {
+            forces[i] = self.calc_force_buffered(objects[i], i, mac, softening, &mut bucket_particles);
+        }
+        forces
     }
 
-    /// Checks if the node is a leaf.
-    pub fn is_leaf(&self) -> bool {
-        for child in &self.children {
-            if child.is_some() {
-                return false
+    /// Removes all nodes, but keeps the allocated memory.
+    /// The cell configures the size of the quadtree.
+    pub fn clear(&mut self, cell: Cell) {
+        self.nodes.clear();
+        self.items.clear();
+        self.order.clear();
+        self.quadrupoles.clear();
+        self.nodes.push(Node::leaf(cell.x, cell.y, 0., cell));
+    }
+
+    /// Rebuilds the quadtree from a set of objects in the already allocated memory.
+    pub fn rebuild(&mut self, objects: &[Object]) {
+        if objects.is_empty() { return self.clear(Cell::new(0., 0., 0.)) }
+
+        self.clear(Self::calc_tree_box_padded(objects, self.padding));
+        for (i, object) in objects.iter().enumerate() {
+            self.insert_below(*object, i, self.max_depth);
+        }
+    }
+
+    /// Updates the quadtree after the objects moved, instead of rebuilding it.
+    /// The objects have to be the ones the quadtree was build from, in the same order,
+    /// new objects can be appended.
+    /// An object stays in its leaf as long as it is inside the cell of the leaf,
+    /// only the objects that left their cell are inserted again from the root.
+    /// Afterwards the centers of mass are calculated again from the leaves up
+    /// and the quadrupole moments have to be calculated again.
+    /// If an object left the cell of the root, the root grows like in insert.
+    /// Returns false if the quadtree could not be refit,
+    /// that is the case if objects were inserted without their index or objects were removed.
+    /// The quadtree is checked before it is changed, but it does not match the objects anymore
+    /// and is unusable until it is rebuilt.
+    pub fn refit(&mut self, objects: &[Object]) -> bool {
+        if self.items.iter().any(|item| item.body >= objects.len()) { return false }
+
+        self.quadrupoles.clear();
+
+        // The objects that are not held by any leaf yet have to be inserted.
+        let mut held = vec![false; objects.len()];
+        let mut moved = Vec::new();
+
+        for index in 0..self.nodes.len() {
+            let node = self.nodes[index];
+            if !node.is_leaf() { continue }
+
+            // The items that stay in the leaf are linked again.
+            let mut leaf = Node { first: NONE, count: 0, ..Node::leaf(0., 0., 0., node.cell) };
+            let mut item = node.first;
+
+            while item != NONE {
+                let Item { body, next, .. } = self.items[item];
+                let object = objects[body];
+
+                held[body] = true;
+                self.items[item].object = object;
+
+                if node.cell.contains(object.pos()) {
+                    self.items[item].next = leaf.first;
+                    leaf.first = item;
+                    leaf.count += 1;
+                    leaf.add(object.x, object.y, object.m);
+                } else {
+                    moved.push(item);
+                }
+                item = next;
             }
+            self.nodes[index] = leaf;
         }
+
+        for (body, object) in objects.iter().enumerate() {
+            if !held[body] {
+                moved.push(self.items.len());
+                self.items.push(Item { object: *object, body, next: NONE });
+            }
+        }
+
+        for item in moved {
+            self.insert_item(item, self.max_depth);
+        }
+        self.update_centers_of_mass();
         true
     }
 
-    /// Adds a child under the node.
-    pub fn new_child(&mut self, quadrant: usize, x: Scalar, y: Scalar, m: Scalar, cell: Cell) {
-        self.children[quadrant] = Some(Self::leaf(x, y, m, cell))
+    /// Is used by refit.
+    /// Calculates the centers of mass and the number of objects of the nodes from their children,
+    /// the leaves have to be up to date already.
+    fn update_centers_of_mass(&mut self) {
+        // Children are always stored behind their parent, so they are calculated first.
+        for index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[index];
+            if node.is_leaf() { continue }
+
+            // The sums are only divided once, which is faster than adding the children one by one.
+            let children = &self.nodes[node.children..node.children + 4];
+            let m: Scalar = children.iter().map(|child| child.m).sum();
+            let count = children.iter().map(|child| child.count).sum();
+
+            let mut occupied = children.iter().filter(|child| child.m != 0.);
+            let (x, y) = match (occupied.next(), occupied.next()) {
+                (None, _) => (node.x, node.y),
+                // A single child is copied exactly, see Node::add.
+                (Some(child), None) => (child.x, child.y),
+                _ => (
+                    children.iter().map(|child| child.m * child.x).sum::<Scalar>() / m,
+                    children.iter().map(|child| child.m * child.y).sum::<Scalar>() / m,
+                ),
+            };
+            self.nodes[index] = Node { x, y, m, count, ..node };
+        }
+    }
+
+    /// Returns the smallest square containing all objects,
+    /// which a quadtree build with the same objects should be based on.
+    pub fn calc_tree_box(objects: &[Object]) -> Cell {
+        Self::calc_tree_box_padded(objects, 0.)
+    }
+
+    /// Returns the smallest square containing all objects,
+    /// enlarged by padding times its size on every side.
+    pub fn calc_tree_box_padded(objects: &[Object], padding: Scalar) -> Cell {
+        let Some(first) = objects.first() else { return Cell::new(0., 0., 1.) };
+
+        let (mut min, mut max) = (first.pos(), first.pos());
+        for object in objects {
+            min.x = min.x.min(object.x); min.y = min.y.min(object.y);
+            max.x = max.x.max(object.x); max.y = max.y.max(object.y);
+        }
+        let size = (max.x - min.x).max(max.y - min.y);
+
+        // If all objects are at the same position any size works.
+        let size = if size > 0. { size } else { 1. };
+        Cell::new(min.x - padding * size, min.y - padding * size, size + 2. * padding * size)
+    }
+
+    /// Inserts an object into the quadtree.
+    /// It is held by a leaf together with at most bucket_size - 1 other objects.
+    /// If the object is outside the cell of the root, the root grows until it contains the object.
+    pub fn insert(&mut self, x: Scalar, y: Scalar, m: Scalar) {
+        self.insert_below(Object::new(x, y, m), NONE, self.max_depth)
+    }
+
+    /// Inserts the object with the index body into the quadtree,
+    /// which is not split up further than max_depth.
+    /// An object with no mass is held with zero weight, so it does not influence gravity,
+    /// but it is found by the spatial queries like every other object.
+    fn insert_below(&mut self, object: Object, body: usize, max_depth: usize) {
+        self.items.push(Item { object, body, next: NONE });
+        self.insert_item(self.items.len() - 1, max_depth);
+    }
+
+    /// Is used by insert_below and refit.
+    /// Links the item into the leaf its object falls into, which is subdivided if it is full.
+    fn insert_item(&mut self, item: usize, max_depth: usize) {
+        let Object { x, y, m } = self.items[item].object;
+        if !self.nodes[0].cell.contains(Vec2::new(x, y)) { self.grow(Vec2::new(x, y)) }
+
+        let mut current: usize = 0;
+
+        for depth in 0.. {
+            let node = self.nodes[current];
+
+            // Objects that can not be split up anymore stay in the same leaf, no matter how many there are.
+            if node.is_leaf() && (node.count < self.bucket_size.max(1) || depth == max_depth) {
+                return self.link(current, item)
+            }
+
+            // The objects of a full leaf must be moved into the children, before the object can be inserted.
+            if node.is_leaf() { self.subdivide(current) }
+
+            let node = &mut self.nodes[current];
+            node.add(x, y, m);
+            node.count += 1;
+            current = node.children + node.cell.quadrant(x, y);
+        }
+    }
+
+    /// Doubles the cell of the root until it contains the point
+    /// and inserts the objects again, because all cells below the root change.
+    /// The size is doubled, so the root only has to grow a few times even if objects keep moving away.
+    fn grow(&mut self, pos: Vec2) {
+        let cell = self.nodes[0].cell.expanded_to(pos);
+
+        let mut items = Vec::with_capacity(self.nodes[0].count);
+        for node in self.nodes.iter().filter(|node| node.is_leaf()) {
+            let mut item = node.first;
+            while item != NONE {
+                items.push(item);
+                item = self.items[item].next;
+            }
+        }
+
+        self.nodes.clear();
+        self.quadrupoles.clear();
+        self.nodes.push(Node::leaf(cell.x, cell.y, 0., cell));
+        for item in items {
+            self.insert_item(item, self.max_depth);
+        }
+    }
+
+    /// Adds the item to the objects of the leaf.
+    fn link(&mut self, leaf: usize, item: usize) {
+        let Object { x, y, m } = self.items[item].object;
+        let node = &mut self.nodes[leaf];
+
+        self.items[item].next = node.first;
+        node.first = item;
+        node.count += 1;
+        node.add(x, y, m);
+    }
+
+    /// Adds four empty children to the leaf and moves the objects it holds into them.
+    /// The center of mass stays the same and does not need to be updated.
+    fn subdivide(&mut self, index: usize) {
+        let node = self.nodes[index];
+        let children = self.nodes.len();
+
+        for quadrant in 0..4 {
+            let cell = node.cell.child(quadrant);
+            self.nodes.push(Node::leaf(cell.x, cell.y, 0., cell));
+        }
+        self.nodes[index].children = children;
+        self.nodes[index].first = NONE;
+
+        let mut item = node.first;
+        while item != NONE {
+            let Item { object, next, .. } = self.items[item];
+            self.link(children + node.cell.quadrant(object.x, object.y), item);
+            item = next;
+        }
+    }
+
+    /// Returns the objects the leaf holds.
+    fn bucket(&self, leaf: usize) -> impl Iterator<Item = &Item> + Clone {
+        let mut item = self.nodes[leaf].first;
+        std::iter::from_fn(move || {
+            let current = self.items.get(item)?;
+            item = current.next;
+            Some(current)
+        })
+    }
+
+    /// Returns the number of nodes, including the empty ones.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Checks if no object has been inserted.
+    pub fn is_empty(&self) -> bool {
+        self.nodes[0].count == 0
+    }
+
+    /// Calculates the quadrupole moments of all nodes from the leaves up,
+    /// which calc_force uses from then on until the quadtree is rebuilt.
+    /// The moment of a node around its center of mass is Q = Σ m * (3 * s * sᵀ - |s|² * I),
+    /// where s is the position of an object relative to the center of mass.
+    pub fn calc_quadrupoles(&mut self) {
+        self.quadrupoles.clear();
+        self.quadrupoles.resize(self.nodes.len(), [0.; 3]);
+
+        // Children are always stored behind their parent, so they are calculated first.
+        for index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[index];
+            if node.is_leaf() {
+                self.quadrupoles[index] = self.bucket(index).fold([0.; 3], |mut quadrupole, item| {
+                    let Object { x, y, m } = item.object;
+                    let (sx, sy) = (x - node.x, y - node.y);
+                    let s_sqr = sx * sx + sy * sy;
+
+                    quadrupole[0] += m * (3. * sx * sx - s_sqr);
+                    quadrupole[1] += m * 3. * sx * sy;
+                    quadrupole[2] += m * (3. * sy * sy - s_sqr);
+                    quadrupole
+                });
+                continue
+            }
+
+            let mut quadrupole = [0.; 3];
+            for child in node.children..node.children + 4 {
+                let Node { x, y, m, .. } = self.nodes[child];
+                if m == 0. { continue }
+
+                // The moment of the child is shifted to the center of mass of the node (parallel axis theorem).
+                let (sx, sy) = (x - node.x, y - node.y);
+                let s_sqr = sx * sx + sy * sy;
+                let [qxx, qxy, qyy] = self.quadrupoles[child];
+
+                quadrupole[0] += qxx + m * (3. * sx * sx - s_sqr);
+                quadrupole[1] += qxy + m * 3. * sx * sy;
+                quadrupole[2] += qyy + m * (3. * sy * sy - s_sqr);
+            }
+            self.quadrupoles[index] = quadrupole;
+        }
     }
 
     /// Calculates the total force that acts on the provided body
-    /// for the accuracy θ.
-    pub fn calc_force(&self, obj: Object, theta: Scalar) -> Vec2 {
+    /// for the accuracy θ and with the softened force kernel.
+    /// If the quadrupole moments were calculated, they are added for the accepted nodes.
+    pub fn calc_force(&self, obj: Object, theta: Scalar, softening: Softening) -> Vec2 {
+        self.calc_force_mac(obj, 0, Mac::Classic(theta), softening)
+    }
+
+    /// Calculates the potential energy of the body i in the field of all other objects
+    /// for the accuracy θ, only with the monopoles of the accepted nodes.
+    pub fn calc_potential(&self, obj: Object, i: usize, theta: Scalar, softening: Softening) -> Scalar {
+        let mut potential = 0.;
+
+        for interaction in QuadtreeForceCalculationIterator::new(obj.pos(), Acceptance::new(Mac::Classic(theta), 0), self) {
+            match interaction {
+                // A leaf or a node with a single object could hold the body itself,
+                // so their objects are summed up one by one.
+                Interaction::Node(index) | Interaction::Bucket(index) if self.nodes[index].is_leaf() || self.nodes[index].count == 1 => {
+                    for item in self.bucket(self.leaf_below(index)).filter(|item| item.body != i) {
+                        potential += softening.potential(obj, item.object);
+                    }
+                }
+                Interaction::Node(index) | Interaction::Bucket(index) => {
+                    let Node { x, y, m, .. } = self.nodes[index];
+                    potential += softening.potential(obj, Object::new(x, y, m));
+                }
+            }
+        }
+        potential
+    }
+
+    /// Is used by calc_potential.
+    /// Returns the leaf below a node with a single object, which holds the object.
+    /// A leaf is returned itself.
+    fn leaf_below(&self, mut index: usize) -> usize {
+        while !self.nodes[index].is_leaf() {
+            let children = self.nodes[index].children;
+            index = (children..children + 4).find(|&child| self.nodes[child].count != 0).unwrap_or(children);
+        }
+        index
+    }
+
+    /// Calculates the total force that acts on the object i (its index is needed by Mac::RelativeForce)
+    /// with the provided multipole acceptance criterion.
+    pub fn calc_force_mac(&self, obj: Object, i: usize, mac: Mac, softening: Softening) -> Vec2 {
+        self.calc_force_buffered(obj, i, mac, softening, &mut Particles::new())
+    }
+
+    /// Calculates the total force like calc_force_mac.
+    /// The objects of the accepted buckets are collected in bucket_particles
+    /// and their force is calculated at once with the vectorized kernel.
+    /// The buffer is reused between the objects, so its memory is only allocated once.
+    fn calc_force_buffered(&self, obj: Object, i: usize, mac: Mac, softening: Softening, bucket_particles: &mut Particles) -> Vec2 {
         let mut total_force = Vec2::new(0., 0.);
+        bucket_particles.clear();
+
+        let acceptance = Acceptance::new(mac, i);
+
+        for interaction in QuadtreeForceCalculationIterator::new(obj.pos(), acceptance, self) {
+            match interaction {
+                Interaction::Node(index) => {
+                    let Node { x, y, m, .. } = self.nodes[index];
+                    total_force += softening.force(obj, Object::new(x, y, m));
+
+                    if !self.quadrupoles.is_empty() {
+                        total_force += quadrupole_force(obj, Vec2::new(x, y), self.quadrupoles[index]);
+                    }
+                }
+                Interaction::Bucket(index) => bucket_particles.extend(self.bucket(index).map(|item| item.object)),
+            }
+        }
+        // The body itself is at a distance of zero and does not exert a force.
+        total_force + kernel::force(obj, bucket_particles, softening)
+    }
+
+    /// Calculates the total force for every object.
+    pub fn calc_forces(&self, objects: &[Object], mac: Mac, softening: Softening) -> Vec<Vec2> {
+        let mut bucket_particles = Particles::new();
+        objects.iter().enumerate().map(|(i, object)| self.calc_force_buffered(*object, i, mac, softening, &mut bucket_particles)).collect()
+    }
+
+    /// Calculates the total force for every object on the threads of the pool.
+    /// The threads steal the objects from each other, so no thread is idle while others still work.
+    /// The quadtree is only read and every force is written directly into its place of the result,
+    /// so no locks are needed.
+    pub fn calc_forces_parallel(&self, objects: &[Object], mac: Mac, softening: Softening, pool: &ThreadPool) -> Vec<Vec2> {
+        pool.install(|| {
+            objects.par_iter().enumerate()
+                .map_init(Particles::new, |bucket_particles, (i, object)| self.calc_force_buffered(*object, i, mac, softening, bucket_particles))
+                .collect()
+        })
+    }
+
+    /// Calculates the total force for every object with one walk through the quadtree per leaf,
+    /// instead of one per object. The nodes are accepted for the whole cell of the leaf,
+    /// so the interaction list is shared by all objects of the leaf
+    /// and their forces are calculated from it with the vectorized kernel.
+    /// The objects have to be the ones the quadtree was build from, in the same order,
+    /// the forces are returned in that order.
+    pub fn calc_forces_grouped(&self, objects: &[Object], mac: Mac, softening: Softening) -> Vec<Vec2> {
+        let mut forces = vec![Vec2::new(0., 0.); objects.len()];
+        let mut interactions = Interactions::default();
 
-        type QTFCI<'a> = QuadtreeForceCalculationIterator<'a>;
+        for leaf in self.occupied_leaves() {
+            self.calc_group_forces(leaf, objects, mac, softening, &mut interactions, &mut |i, force| forces[i] = force);
+        }
+        forces
+    }
 
-        for obj2 in QTFCI::new(obj.pos(), theta, self) {
-            let r_vec = obj2.pos() - obj.pos();
+    /// Calculates the total force for every object like calc_forces_grouped,
+    /// with the leaves spread over the threads of the pool.
+    pub fn calc_forces_grouped_parallel(&self, objects: &[Object], mac: Mac, softening: Softening, pool: &ThreadPool) -> Vec<Vec2> {
+        let leaves: Vec<usize> = self.occupied_leaves().collect();
 
-            if r_vec.length() == 0. { continue; }
+        let group_forces: Vec<Vec<(usize, Vec2)>> = pool.install(|| {
+            leaves.par_iter()
+                .map_init(Interactions::default, |interactions, &leaf| {
+                    let mut group_forces = Vec::with_capacity(self.nodes[leaf].count);
+                    self.calc_group_forces(leaf, objects, mac, softening, interactions, &mut |i, force| group_forces.push((i, force)));
+                    group_forces
+                })
+                .collect()
+        });
 
-            let force = G * ((obj.m * obj2.m) / r_vec.length().powf(3.)) * r_vec;
-            total_force += force;
+        let mut forces = vec![Vec2::new(0., 0.); objects.len()];
+        for (i, force) in group_forces.into_iter().flatten() {
+            forces[i] = force;
         }
-        total_force
+        forces
+    }
+
+    /// Returns the indices of the leaves that hold objects with a mass.
+    /// The objects without a mass are not accelerated, so leaves with only those are skipped.
+    fn occupied_leaves(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.nodes.len()).filter(|&index| self.nodes[index].is_leaf() && self.nodes[index].m != 0.)
     }
 
-    /// Calls the provided function for every node with the nodes properties.
+    /// Is used by calc_forces_grouped and calc_forces_grouped_parallel.
+    /// Collects the interaction list of the leaf and passes the force onto each of its objects to set_force.
+    fn calc_group_forces(
+        &self, leaf: usize, objects: &[Object], mac: Mac, softening: Softening,
+        interactions: &mut Interactions, set_force: &mut impl FnMut(usize, Vec2),
+    ) {
+        // Objects that were inserted without their index do not get a force.
+        let bodies = self.bucket(leaf).map(|item| item.body).filter(|&i| i < objects.len());
+        let acceptance = Acceptance::for_group(mac, bodies.clone());
+        self.collect_interactions(self.nodes[leaf].cell, acceptance, interactions);
+
+        for i in bodies {
+            let obj = objects[i];
+            // The body itself is at a distance of zero and does not exert a force.
+            let mut force = kernel::force(obj, &interactions.particles, softening);
+
+            if !self.quadrupoles.is_empty() {
+                for &index in &interactions.nodes {
+                    let Node { x, y, .. } = self.nodes[index];
+                    force += quadrupole_force(obj, Vec2::new(x, y), self.quadrupoles[index]);
+                }
+            }
+            set_force(i, force);
+        }
+    }
+
+    /// Collects the nodes that are far enough away from the whole group cell to be used as a whole
+    /// and the objects of the leaves that are too close.
+    fn collect_interactions(&self, group: Cell, acceptance: Acceptance, interactions: &mut Interactions) {
+        interactions.particles.clear();
+        interactions.nodes.clear();
+        interactions.sub_trees.clear();
+        interactions.sub_trees.push(0);
+
+        while let Some(index) = interactions.sub_trees.pop() {
+            let node = &self.nodes[index];
+
+            // Empty cells do not exert a force.
+            if node.m == 0. { continue }
+
+            if acceptance.accepts_group(node, group) {
+                interactions.particles.push(Object::new(node.x, node.y, node.m));
+                interactions.nodes.push(index);
+            } else if node.is_leaf() {
+                interactions.particles.extend(self.bucket(index).map(|item| item.object));
+            } else {
+                interactions.sub_trees.extend(node.children..node.children + 4);
+            }
+        }
+    }
+
+    /// Calls the provided function for every non empty node with the nodes properties.
     /// The functions parameters should be fn(node_x, node_y, node_m, cell, level).
     pub fn do_on_nodes<T>(&self, f: &mut impl FnMut(Scalar, Scalar, Scalar, Cell, usize) -> T) -> T {
-        self.do_on_nodes_helper_func(0, f)
+        self.do_on_nodes_helper_func(0, 0, f)
     }
 
     /// Is used by do_on_nodes.
     /// Needs to be given a starting level to correctly calculate the current level.
     /// The provided level must always be zero.
-    /// Calls the provided function for every node with the nodes properties.
+    /// Calls the provided function for every non empty node with the nodes properties.
     /// The functions parameters should be fn(node_x, node_y, node_m, cell, level).
-    fn do_on_nodes_helper_func<T>(&self, level: usize, f: &mut impl FnMut(Scalar, Scalar, Scalar, Cell, usize) -> T) -> T {
-        let result = f(self.x, self.y, self.m, self.cell, level);
-        for opt_child in &self.children {
-            if let Some(child) = opt_child {
-                child.do_on_nodes_helper_func(level + 1, f);
+    fn do_on_nodes_helper_func<T>(&self, index: usize, level: usize, f: &mut impl FnMut(Scalar, Scalar, Scalar, Cell, usize) -> T) -> T {
+        let node = self.nodes[index];
+        let result = f(node.x, node.y, node.m, node.cell, level);
+
+        if !node.is_leaf() {
+            for child in node.children..node.children + 4 {
+                if self.nodes[child].count != 0 {
+                    self.do_on_nodes_helper_func(child, level + 1, f);
+                }
             }
         }
         result
     }
 
-    /// Returns a list of all the leaves.
-    /// TODO Probably very memory intese and slow at the moment.
-    pub fn get_leaves(&self) -> Vec<Self> {
-        let mut leaves = Vec::<Quadtree>::new();
-        let mut no_child: u8 = 1;
+    /// Returns the objects the leaves hold.
+    pub fn get_leaves(&self) -> Vec<Object> {
+        (0..self.nodes.len())
+            .filter(|&index| self.nodes[index].is_leaf())
+            .flat_map(|index| self.bucket(index).map(|item| item.object))
+            .collect()
+    }
+
+    /// Returns every pair (i, j) with i < j of objects whose circles with the radii overlap,
+    /// like collision::overlapping_pairs, but only the objects that are close are compared.
+    /// The quadtree has to hold the objects with their index at their current positions (see refit).
+    pub fn overlapping_pairs(&self, objects: &[Object], radii: &[Scalar]) -> Vec<(usize, usize)> {
+        let max_radius = radii.iter().copied().fold(0., Scalar::max);
+        let mut pairs = Vec::new();
+        let mut sub_trees = Vec::new();
+
+        for (i, object) in objects.iter().enumerate() {
+            // Any object that overlaps with this one is at most this far away.
+            let reach = radii[i] + max_radius;
+            let (min, max) = (object.pos() - Vec2::new(reach, reach), object.pos() + Vec2::new(reach, reach));
+
+            self.visit_items_in(min, max, &mut sub_trees, |item| {
+                let j = item.body;
+                // The objects find each other, so the pair is only reported by the first one.
+                if j >= objects.len() || j <= i { return }
+
+                if collision::overlap(object.pos(), radii[i], objects[j].pos(), radii[j]) {
+                    pairs.push((i.min(j), i.max(j)));
+                }
+            });
+        }
+        pairs.sort_unstable();
+        pairs
+    }
+
+    /// Returns the indices of the objects inside the rectangle from min to max, including its boundary.
+    /// Objects without a mass are found as well, only the ones inserted without their index are not.
+    pub fn bodies_in_rect(&self, min: Vec2, max: Vec2) -> Vec<usize> {
+        let mut bodies = Vec::new();
+        self.visit_items_in(min, max, &mut Vec::new(), |item| {
+            let Object { x, y, .. } = item.object;
+            if item.body != NONE && min.x <= x && x <= max.x && min.y <= y && y <= max.y {
+                bodies.push(item.body);
+            }
+        });
+        bodies
+    }
+
+    /// Returns the indices of the objects at most the radius away from the center, see bodies_in_rect.
+    pub fn bodies_in_radius(&self, center: Vec2, radius: Scalar) -> Vec<usize> {
+        let (min, max) = (center - Vec2::new(radius, radius), center + Vec2::new(radius, radius));
+
+        let mut bodies = Vec::new();
+        self.visit_items_in(min, max, &mut Vec::new(), |item| {
+            if item.body != NONE && (item.object.pos() - center).length() <= radius {
+                bodies.push(item.body);
+            }
+        });
+        bodies
+    }
+
+    /// Returns the indices of the k objects closest to the position, the closest first, see bodies_in_rect.
+    /// The nodes are visited in the order of their distance,
+    /// so the walk ends as soon as the next node is further away than the k-th closest object so far.
+    pub fn nearest(&self, pos: Vec2, k: usize) -> Vec<usize> {
+        if k == 0 { return Vec::new() }
+
+        // The k closest objects so far, the furthest one on top.
+        let mut closest = BinaryHeap::<Candidate>::with_capacity(k + 1);
+        // The nodes that still have to be visited, the closest one on top.
+        let mut nodes = BinaryHeap::from([Reverse(Candidate { distance: self.nodes[0].cell.distance(pos), index: 0 })]);
 
-        for child in &self.children {
-            if child.is_some() {
-                leaves.append(&mut child.clone().unwrap().get_leaves());
+        while let Some(Reverse(Candidate { distance, index })) = nodes.pop() {
+            if closest.len() == k && closest.peek().is_some_and(|furthest| furthest.distance <= distance) { break }
+
+            let node = &self.nodes[index];
+            if node.count == 0 { continue }
+
+            if node.is_leaf() {
+                for item in self.bucket(index).filter(|item| item.body != NONE) {
+                    closest.push(Candidate { distance: (item.object.pos() - pos).length(), index: item.body });
+                    if closest.len() > k { closest.pop(); }
+                }
             } else {
-                no_child += 1;
+                for child in node.children..node.children + 4 {
+                    nodes.push(Reverse(Candidate { distance: self.nodes[child].cell.distance(pos), index: child }));
+                }
             }
         }
-        if no_child == 4 {
-            leaves.push(self.clone());
+        closest.into_sorted_vec().into_iter().map(|candidate| candidate.index).collect()
+    }
+
+    /// Calls the function for every item of the leaves whose cells intersect the rectangle from min to max.
+    /// The sub_trees are the memory for the walk, which can be reused between calls.
+    fn visit_items_in(&self, min: Vec2, max: Vec2, sub_trees: &mut Vec<usize>, mut function: impl FnMut(&Item)) {
+        sub_trees.clear();
+        sub_trees.push(0);
+
+        while let Some(index) = sub_trees.pop() {
+            let node = &self.nodes[index];
+            if node.count == 0 || !node.cell.intersects(min, max) { continue }
+
+            if node.is_leaf() {
+                self.bucket(index).for_each(&mut function);
+            } else {
+                sub_trees.extend(node.children..node.children + 4);
+            }
         }
-        leaves
     }
 }
 
@@ -208,45 +961,186 @@ impl std::fmt::Display for Quadtree {
             for _ in 0..level {
                 write!(formatter, "  |  ")?;
             }
-            write!(formatter, "({:.2}|{:.2}|{:.2} | {cell})\n", x, y, m)
+            writeln!(formatter, "({:.2}|{:.2}|{:.2} | {cell})", x, y, m)
         };
 
         self.do_on_nodes(&mut fmt_helper)
     }
 }
 
+/// Returns the force the quadrupole moment of a node with the center of mass com exerts on obj.
+/// It is not softened, because nodes are only accepted at a distance.
+/// With d = obj - com, the potential of the moment is -G * dᵀ * Q * d / (2 * |d|⁵), so the force is
+/// G * m * (Q * d / |d|⁵ - 5/2 * dᵀ * Q * d * d / |d|⁷).
+fn quadrupole_force(obj: Object, com: Vec2, quadrupole: [Scalar; 3]) -> Vec2 {
+    let d = obj.pos() - com;
+    let d_sqr = d.dot(d);
+    if d_sqr == 0. { return Vec2::new(0., 0.) }
+
+    let [qxx, qxy, qyy] = quadrupole;
+    let q_d = Vec2::new(qxx * d.x + qxy * d.y, qxy * d.x + qyy * d.y);
+    let d_q_d = d.dot(q_d);
+
+    let d5_inv = 1. / (d_sqr * d_sqr * d_sqr.sqrt());
+    G * obj.m * d5_inv * (q_d - (2.5 * d_q_d / d_sqr) * d)
+}
+
+/// A node or an object with its distance, which is ordered by the distance, see Quadtree::nearest.
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    distance: Scalar,
+    index: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.total_cmp(&other.distance).then(self.index.cmp(&other.index))
+    }
+}
+
+/// The interaction list of a group walk, see Quadtree::calc_forces_grouped.
+/// It is kept between the groups, so its memory is reused.
+#[derive(Debug, Default)]
+struct Interactions {
+    /// The centers of mass of the accepted nodes and the objects of the leaves that are too close.
+    particles: Particles,
+    /// The indices of the accepted nodes, which are needed for their quadrupole moments.
+    nodes: Vec<usize>,
+    /// The nodes that still have to be visited.
+    sub_trees: Vec<usize>,
+}
+
+/// The multipole acceptance criterion for a single object or a group of objects.
+#[derive(Debug, Clone, Copy)]
+enum Acceptance {
+    Classic(Scalar),
+    BMax(Scalar),
+    MinDistance(Scalar),
+    /// α * |a_old| / G
+    RelativeForce(Scalar),
+}
+
+impl Acceptance {
+    fn new(mac: Mac, i: usize) -> Self {
+        match mac {
+            Mac::Classic(theta) => Self::Classic(theta),
+            Mac::BMax(theta) => Self::BMax(theta),
+            Mac::MinDistance(theta) => Self::MinDistance(theta),
+            Mac::RelativeForce(alpha, theta, accelerations) => {
+                match accelerations.get(i).map(|acc| acc.length()) {
+                    Some(acc) if acc > 0. => Self::RelativeForce(alpha * acc / G),
+                    // Without the acceleration of the previous step the classic criterion is used.
+                    _ => Self::Classic(theta),
+                }
+            }
+        }
+    }
+
+    /// Creates the criterion for a group of objects.
+    /// Mac::RelativeForce uses the smallest acceleration of the group.
+    fn for_group(mac: Mac, bodies: impl Iterator<Item = usize>) -> Self {
+        match mac {
+            Mac::RelativeForce(alpha, theta, accelerations) => {
+                let smallest = bodies
+                    .map(|i| accelerations.get(i).map_or(0., |acc| acc.length()))
+                    .fold(Scalar::INFINITY, Scalar::min);
+
+                match smallest {
+                    acc if acc > 0. && acc.is_finite() => Self::RelativeForce(alpha * acc / G),
+                    // Without the acceleration of the previous step the classic criterion is used.
+                    _ => Self::Classic(theta),
+                }
+            }
+            _ => Self::new(mac, 0),
+        }
+    }
+
+    /// Checks if the node is far enough away from every point of the group cell to be used as a whole.
+    /// The distances are measured to the closest point of the group cell instead of to an object.
+    fn accepts_group(&self, node: &Node, group: Cell) -> bool {
+        let size = node.cell.size;
+        let distance = group.distance(Vec2::new(node.x, node.y));
+
+        match *self {
+            Self::Classic(theta) => size < theta * distance,
+            Self::BMax(theta) => node.b_max() < theta * distance,
+            Self::MinDistance(theta) => size < theta * node.cell.distance_to_cell(&group),
+            Self::RelativeForce(acc) => {
+                node.cell.distance_to_cell(&group) > 0. && node.m * size * size <= acc * distance.powi(4)
+            }
+        }
+    }
+
+    /// Checks if the node is far enough away from pos to be used as a whole.
+    fn accepts(&self, node: &Node, pos: Vec2) -> bool {
+        let com = Vec2::new(node.x, node.y);
+        let size = node.cell.size;
+
+        match *self {
+            Self::Classic(theta) => size / (com - pos).length() < theta,
+            Self::BMax(theta) => node.b_max() < theta * (com - pos).length(),
+            Self::MinDistance(theta) => size < theta * node.cell.distance(pos),
+            Self::RelativeForce(acc) => {
+                // G * m * size² / r⁴ <= α * |a_old|, like in Gadget.
+                // Nodes that contain the object are always opened.
+                let r_sqr = (com - pos).dot(com - pos);
+                node.cell.distance(pos) > 0. && node.m * size * size <= acc * r_sqr * r_sqr
+            }
+        }
+    }
+}
+
 struct QuadtreeForceCalculationIterator<'a> {
     pos: Vec2,
-    theta: Scalar,
-    sub_trees: Vec<&'a Quadtree>,
+    acceptance: Acceptance,
+    qtree: &'a Quadtree,
+    sub_trees: Vec<usize>,
 }
 
 impl<'a> QuadtreeForceCalculationIterator<'a> {
-    pub fn new(pos: Vec2, theta: Scalar, qtree: &'a Quadtree) -> Self {
-        Self { pos, theta, sub_trees: vec![qtree], }
+    fn new(pos: Vec2, acceptance: Acceptance, qtree: &'a Quadtree) -> Self {
+        Self { pos, acceptance, qtree, sub_trees: vec![0], }
     }
 }
 
-/// Returns the next Object that is needed to calculate the total force.
-/// The returned Object represents a center of masse or a single mass.
-impl<'a> Iterator for QuadtreeForceCalculationIterator<'a> {
-    type Item = Object;
+/// A node that is needed to calculate the total force.
+enum Interaction {
+    /// The node is far enough away to be used as a whole.
+    Node(usize),
+    /// The leaf is too close, so the forces of its objects are calculated directly.
+    Bucket(usize),
+}
+
+/// Returns the next node that is needed to calculate the total force.
+impl Iterator for QuadtreeForceCalculationIterator<'_> {
+    type Item = Interaction;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while !self.sub_trees.is_empty() {
-            let sub_tree = self.sub_trees.pop()?;
+        while let Some(index) = self.sub_trees.pop() {
+            let sub_tree = &self.qtree.nodes[index];
 
-            let distance = (Vec2::new(sub_tree.x, sub_tree.y) - self.pos).length();
-            
-            if sub_tree.cell.size / distance < self.theta || sub_tree.is_leaf() {
-                return Some(Object::new(sub_tree.x, sub_tree.y, sub_tree.m))
-            }
+            // Empty cells do not exert a force.
+            if sub_tree.m == 0. { continue }
 
-            for opt_child in &sub_tree.children {
-                if let Some(child_tree) = opt_child {
-                    self.sub_trees.push(&child_tree);
-                }
-            }
+            // A leaf with a single object is the same as the object, so it is not checked.
+            if sub_tree.count == 1 || self.acceptance.accepts(sub_tree, self.pos) { return Some(Interaction::Node(index)) }
+            if sub_tree.is_leaf() { return Some(Interaction::Bucket(index)) }
+
+            self.sub_trees.extend(sub_tree.children..sub_tree.children + 4);
         }
         None
     }
@@ -255,8 +1149,12 @@ impl<'a> Iterator for QuadtreeForceCalculationIterator<'a> {
 #[cfg(test)]
 mod quadtree_tests {
     use crate::base::*;
-    use crate::barnes_hut::Quadtree;
-    
+    use crate::barnes_hut::{Quadtree, Cell, Mac, BUCKET_SIZE, MAX_DEPTH};
+    use crate::direct::{get_forces, get_potential_energy};
+    use crate::collision;
+    use crate::tests::objects;
+    use rayon::ThreadPoolBuilder;
+
     #[test]
     fn test_tree_creation() {
         let mut objects = Vec::<Object>::new();
@@ -267,13 +1165,402 @@ mod quadtree_tests {
         objects.remove(4);
 
         let tree_box = Quadtree::calc_tree_box(&objects);
-        let qtree = Quadtree::create_from_objects(objects.clone(), tree_box);
-
+        let qtree = Quadtree::create_from_objects(&objects, tree_box);
 
         println!("objects:\n");
-        for object in objects {
+        for object in &objects {
             println!("{:?}\n", object);
         }
         println!("\nQuadtree ({}):\n{}", tree_box, qtree);
+
+        assert_eq!(qtree.get_leaves().len(), objects.len());
+    }
+
+    #[test]
+    fn test_rebuild_reuses_the_arena() {
+        let objects = objects(100, 101., 97., |_| 1e10);
+
+        let mut qtree = Quadtree::create_from_objects(&objects, Quadtree::calc_tree_box(&objects));
+        let len = qtree.len();
+        let exact = get_forces(&objects, Softening::default());
+
+        // A rebuild with the same objects must result in the same tree.
+        qtree.rebuild(&objects);
+        assert_eq!(qtree.len(), len);
+
+        for (object, exact_force) in objects.iter().zip(&exact) {
+            let force = qtree.calc_force(*object, 0., Softening::default());
+            assert!((force - *exact_force).length() <= 1e-3 * exact_force.length());
+        }
+    }
+
+    #[test]
+    fn test_parallel_forces_match() {
+        let objects = objects(1000, 101., 97., |_| 1e10);
+        let qtree = Quadtree::create_from_objects(&objects, Quadtree::calc_tree_box(&objects));
+        let forces = qtree.calc_forces(&objects, Mac::Classic(0.5), Softening::default());
+
+        for threads in [1, 3, 8] {
+            let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let parallel_forces = qtree.calc_forces_parallel(&objects, Mac::Classic(0.5), Softening::default(), &pool);
+            assert_eq!(forces, parallel_forces);
+        }
+    }
+
+    #[test]
+    fn test_parallel_build_matches_serial_build() {
+        let objects = objects(10_000, 1009., 997., |i| 1e10 + i);
+        let cell = Quadtree::calc_tree_box(&objects);
+
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+
+        let sort = |leaves: Vec<Object>| {
+            let mut leaves: Vec<_> = leaves.into_iter().map(|leaf| (leaf.x, leaf.y, leaf.m)).collect();
+            leaves.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            leaves
+        };
+
+        // A bucket larger than the parallel cutoff must not be split up either.
+        for bucket_size in [BUCKET_SIZE, 3000] {
+            let mut qtree = Quadtree::with_limits(cell, bucket_size, MAX_DEPTH);
+            qtree.rebuild(&objects);
+            let mut parallel_qtree = Quadtree::with_limits(cell, bucket_size, MAX_DEPTH);
+            parallel_qtree.rebuild_parallel(&objects, &pool);
+
+            assert_eq!(qtree.len(), parallel_qtree.len());
+            assert_eq!(qtree.nodes.len(), parallel_qtree.nodes.len());
+            assert_eq!(sort(qtree.get_leaves()), sort(parallel_qtree.get_leaves()));
+
+            let forces = qtree.calc_forces(&objects, Mac::Classic(0.5), Softening::default());
+            let parallel_forces = parallel_qtree.calc_forces(&objects, Mac::Classic(0.5), Softening::default());
+            for (force, parallel_force) in forces.iter().zip(&parallel_forces) {
+                assert!((*force - *parallel_force).length() <= 1e-3 * force.length());
+            }
+        }
+    }
+
+    #[test]
+    fn test_morton_build_matches_insertion_build() {
+        let objects = objects(5_000, 1009., 997., |i| if i % 10. == 0. { 0. } else { 1e10 + i });
+        let cell = Quadtree::calc_tree_box(&objects);
+
+        let qtree = Quadtree::create_from_objects(&objects, cell);
+        let morton_qtree = Quadtree::create_from_objects_morton(&objects, cell);
+
+        assert_eq!(qtree.len(), morton_qtree.len());
+        assert_eq!(qtree.get_leaves().len(), morton_qtree.get_leaves().len());
+
+        let forces = qtree.calc_forces(&objects, Mac::Classic(0.5), Softening::default());
+        let morton_forces = morton_qtree.calc_forces_morton(&objects, Mac::Classic(0.5), Softening::default());
+        // The centers of mass are added up in a different order,
+        // which can change if a node close to the limit of θ is accepted.
+        for (force, morton_force) in forces.iter().zip(&morton_forces) {
+            assert!((*force - *morton_force).length() <= 1e-2 * force.length());
+        }
+    }
+
+    #[test]
+    fn test_refit_matches_rebuild() {
+        let mut objects = objects(2000, 1009., 997., |i| if i % 10. == 0. { 0. } else { 1e10 + i });
+        let cell = Quadtree::calc_tree_box(&objects);
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+
+        let mut qtrees = [
+            Quadtree::create_from_objects(&objects, cell),
+            Quadtree::create_from_objects_morton(&objects, cell),
+            Quadtree::create_from_objects_parallel(&objects, cell, &pool),
+        ];
+
+        for step in 0..5 {
+            // The objects move towards the center, some of them into other cells,
+            // and some of them gain or lose their mass.
+            for (i, object) in objects.iter_mut().enumerate() {
+                object.x += (500. - object.x) * 0.01 * (i % 7) as Scalar;
+                object.y += (500. - object.y) * 0.01 * (i % 5) as Scalar;
+                if i % 97 == step { object.m = if object.m == 0. { 1e10 } else { 0. } }
+            }
+            let exact = get_forces(&objects, Softening::default());
+
+            for qtree in &mut qtrees {
+                assert!(qtree.refit(&objects));
+
+                for (object, exact_force) in objects.iter().zip(&exact) {
+                    let force = qtree.calc_force(*object, 0., Softening::default());
+                    assert!((force - *exact_force).length() <= 1e-3 * exact_force.length());
+                }
+            }
+        }
+
+        // The root grows, if an object leaves it.
+        objects[0] = Object::new(-1e4, 0., 1e10);
+        let exact = get_forces(&objects, Softening::default());
+        for qtree in &mut qtrees {
+            assert!(qtree.refit(&objects));
+
+            for (object, exact_force) in objects.iter().zip(&exact) {
+                let force = qtree.calc_force(*object, 0., Softening::default());
+                assert!((force - *exact_force).length() <= 1e-3 * exact_force.length());
+            }
+        }
+
+        // Without some of the objects the quadtree is not refit and not changed either.
+        for qtree in &mut qtrees {
+            let held = |qtree: &Quadtree| qtree.nodes.iter().filter(|node| node.is_leaf()).map(|node| node.count).sum::<usize>();
+            let moved: Vec<Object> = objects[..1000].iter().map(|object| Object::new(object.y, object.x, object.m)).collect();
+            assert!(!qtree.refit(&moved));
+            assert_eq!(held(qtree), objects.len());
+        }
+    }
+
+    #[test]
+    fn test_buckets_and_max_depth() {
+        // Objects at the same position can not be split up.
+        let mut objects = objects(1000, 1009., 997., |i| 1e10 + i);
+        objects[..50].fill(Object::new(500., 500., 1e10));
+        let cell = Quadtree::calc_tree_box(&objects);
+        let exact = get_forces(&objects, Softening::default());
+
+        for bucket_size in [1, 4, 16] {
+            let mut qtree = Quadtree::with_limits(cell, bucket_size, 10);
+            qtree.rebuild(&objects);
+            let mut morton_qtree = Quadtree::with_limits(cell, bucket_size, 10);
+            morton_qtree.rebuild_morton(&objects);
+
+            for qtree in [&qtree, &morton_qtree] {
+                assert_eq!(qtree.get_leaves().len(), objects.len());
+
+                // Only the leaves at the maximum depth hold more objects than the bucket size.
+                let smallest = qtree.nodes[0].cell.size / 1024.;
+                for node in qtree.nodes.iter().filter(|node| node.is_leaf() && node.count > bucket_size) {
+                    assert!(node.cell.size <= smallest * 1.001);
+                }
+
+                // The objects of close leaves are used directly, so θ = 0 is exact.
+                for (object, exact_force) in objects.iter().zip(&exact) {
+                    let force = qtree.calc_force(*object, 0., Softening::default());
+                    assert!((force - *exact_force).length() <= 1e-3 * exact_force.length());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_tree_box() {
+        // An off-center cloud, that is wider than high.
+        let objects: Vec<Object> = objects(100, 101., 31., |_| 1.).into_iter()
+            .map(|object| Object::new(1000. + object.x, -500. + object.y, object.m))
+            .collect();
+
+        let cell = Quadtree::calc_tree_box(&objects);
+        assert_eq!((cell.x, cell.y, cell.size), (1000., -500., 100.));
+        assert!(objects.iter().all(|object| cell.contains(object.pos())));
+
+        let padded = Quadtree::calc_tree_box_padded(&objects, 0.1);
+        assert_eq!((padded.x, padded.y, padded.size), (990., -510., 120.));
+
+        // All objects at the same position still result in a cell with a size.
+        let cell = Quadtree::calc_tree_box(&[Object::new(3., 4., 1.); 2]);
+        assert_eq!((cell.x, cell.y, cell.size), (3., 4., 1.));
+    }
+
+    #[test]
+    fn test_root_grows() {
+        let objects: Vec<Object> = objects(1000, 1009., 997., |i| 1e10 + i).into_iter()
+            .map(|object| Object::new(object.x - 500., object.y - 500., object.m))
+            .collect();
+        let exact = get_forces(&objects, Softening::default());
+
+        // The cell only contains a part of the objects.
+        let cell = Cell::new(0., 0., 10.);
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let qtrees = [
+            Quadtree::create_from_objects(&objects, cell),
+            Quadtree::create_from_objects_morton(&objects, cell),
+            Quadtree::create_from_objects_parallel(&objects, cell, &pool),
+        ];
+
+        for qtree in &qtrees {
+            assert_eq!(qtree.get_leaves().len(), objects.len());
+            assert!(objects.iter().all(|object| qtree.nodes[0].cell.contains(object.pos())));
+
+            for (object, exact_force) in objects.iter().zip(&exact) {
+                let force = qtree.calc_force(*object, 0., Softening::default());
+                assert!((force - *exact_force).length() <= 1e-3 * exact_force.length());
+            }
+        }
+    }
+
+    #[test]
+    fn test_quadrupoles_are_more_accurate() {
+        let objects = objects(2000, 1009., 997., |i| 1e10 + 1e7 * (i % 13.));
+        let exact = get_forces(&objects, Softening::default());
+
+        let mut qtree = Quadtree::create_from_objects(&objects, Quadtree::calc_tree_box(&objects));
+        let error = |qtree: &Quadtree| -> Scalar {
+            let forces = qtree.calc_forces(&objects, Mac::Classic(0.7), Softening::default());
+            forces.iter().zip(&exact).map(|(force, exact)| (*force - *exact).length() / exact.length()).sum()
+        };
+
+        let monopole_error = error(&qtree);
+        qtree.calc_quadrupoles();
+        let quadrupole_error = error(&qtree);
+
+        assert!(quadrupole_error < 0.5 * monopole_error);
+    }
+
+    #[test]
+    fn test_acceptance_criteria() {
+        let objects = objects(2000, 1009., 997., |i| 1e10 + 1e7 * (i % 13.));
+        let exact = get_forces(&objects, Softening::default());
+        let accelerations: Vec<Vec2> = exact.iter().zip(&objects).map(|(force, object)| *force / object.m).collect();
+
+        let qtree = Quadtree::create_from_objects(&objects, Quadtree::calc_tree_box(&objects));
+        let error = |mac: Mac| -> Scalar {
+            let forces = qtree.calc_forces(&objects, mac, Softening::default());
+            let errors = forces.iter().zip(&exact).map(|(force, exact)| (*force - *exact).length() / exact.length());
+            errors.sum::<Scalar>() / objects.len() as Scalar
+        };
+
+        // The mean relative error stays small for every criterion.
+        for mac in [Mac::Classic(0.3), Mac::BMax(0.3), Mac::MinDistance(0.3), Mac::RelativeForce(0.001, 0.3, &accelerations)] {
+            assert!(error(mac) < 0.01);
+        }
+        // Without the previous accelerations the relative criterion falls back to the classic one with its θ.
+        assert_eq!(error(Mac::RelativeForce(0.001, 0.3, &[])), error(Mac::Classic(0.3)));
+        assert_ne!(error(Mac::RelativeForce(0.001, 0.3, &[])), error(Mac::Classic(0.5)));
+    }
+
+    #[test]
+    fn test_grouped_forces() {
+        let objects = objects(2000, 1009., 997., |i| 1e10 + 1e7 * (i % 13.));
+        let exact = get_forces(&objects, Softening::default());
+        let accelerations: Vec<Vec2> = exact.iter().zip(&objects).map(|(force, object)| *force / object.m).collect();
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+
+        let mut qtree = Quadtree::create_from_objects_morton(&objects, Quadtree::calc_tree_box(&objects));
+        let error = |forces: Vec<Vec2>| -> Scalar {
+            let errors = forces.iter().zip(&exact).map(|(force, exact)| (*force - *exact).length() / exact.length());
+            errors.sum::<Scalar>() / objects.len() as Scalar
+        };
+
+        // For θ = 0 every object interacts with every other one directly.
+        assert!(error(qtree.calc_forces_grouped(&objects, Mac::Classic(0.), Softening::default())) < 1e-4);
+
+        // A node accepted for the whole leaf is accepted for each of its objects as well,
+        // so the grouped walk is at least as accurate as the walk per object.
+        for mac in [Mac::Classic(0.7), Mac::BMax(0.7), Mac::MinDistance(0.7), Mac::RelativeForce(0.001, 0.7, &accelerations)] {
+            let grouped_error = error(qtree.calc_forces_grouped(&objects, mac, Softening::default()));
+            assert!(grouped_error <= 1.01 * error(qtree.calc_forces_morton(&objects, mac, Softening::default())));
+            assert!(grouped_error < 0.05);
+        }
+        // Without the previous accelerations the whole group falls back to the classic criterion with its θ.
+        let fallback_forces = qtree.calc_forces_grouped(&objects, Mac::RelativeForce(0.001, 0.7, &[]), Softening::default());
+        assert_eq!(fallback_forces, qtree.calc_forces_grouped(&objects, Mac::Classic(0.7), Softening::default()));
+
+        qtree.calc_quadrupoles();
+        let forces = qtree.calc_forces_grouped(&objects, Mac::Classic(0.7), Softening::default());
+        let parallel_forces = qtree.calc_forces_grouped_parallel(&objects, Mac::Classic(0.7), Softening::default(), &pool);
+        assert_eq!(forces, parallel_forces);
+        assert!(error(forces) <= error(qtree.calc_forces_morton(&objects, Mac::Classic(0.7), Softening::default())));
+    }
+
+    #[test]
+    fn test_overlapping_pairs() {
+        // Every seventh object has no mass.
+        let objects = objects(500, 1009., 997., |i| if i % 7. == 0. { 0. } else { 1e10 });
+        let radii: Vec<Scalar> = (0..500).map(|i| (i % 5 * 10) as Scalar).collect();
+        let positions: Vec<Vec2> = objects.iter().map(|object| object.pos()).collect();
+
+        let mut qtree = Quadtree::create_from_objects_morton(&objects, Quadtree::calc_tree_box(&objects));
+
+        // The objects without a mass collide with each other as well.
+        let check = |qtree: &Quadtree, objects: &[Object], positions: &[Vec2]| {
+            let expected = collision::overlapping_pairs(positions, &radii);
+            assert!(expected.iter().any(|&(i, j)| objects[i].m == 0. && objects[j].m == 0.));
+            assert_eq!(qtree.overlapping_pairs(objects, &radii), expected);
+        };
+        check(&qtree, &objects, &positions);
+
+        // The refit quadtree finds the pairs at the new positions.
+        let moved: Vec<Object> = objects.iter().map(|object| Object::new(object.y, object.x * 0.5, object.m)).collect();
+        let moved_positions: Vec<Vec2> = moved.iter().map(|object| object.pos()).collect();
+        assert!(qtree.refit(&moved));
+        check(&qtree, &moved, &moved_positions);
+    }
+
+    #[test]
+    fn test_potential_matches_direct() {
+        // Distinct objects at the same position have a softened potential.
+        let mut objects = objects(2000, 1009., 997., |i| 1e10 + 1e7 * (i % 13.));
+        objects.extend([objects[3], objects[3]]);
+        let softening = Softening::new(SofteningKernel::Plummer, 5.);
+        let exact = get_potential_energy(&objects, softening);
+
+        let qtree = Quadtree::create_from_objects_morton(&objects, Quadtree::calc_tree_box(&objects));
+        let potential = |theta: Scalar| {
+            objects.iter().enumerate().map(|(i, object)| qtree.calc_potential(*object, i, theta, softening)).sum::<Scalar>() / 2.
+        };
+
+        assert!((potential(0.) - exact).abs() < 1e-3 * exact.abs());
+        assert!((potential(0.5) - exact).abs() < 1e-2 * exact.abs());
+    }
+
+    #[test]
+    fn test_potential_of_refit_quadtree_matches_direct() {
+        let mut objects = objects(200, 1009., 997., |i| 1e10 + 1e7 * (i % 13.));
+        let mut qtree = Quadtree::create_from_objects_morton(&objects, Quadtree::calc_tree_box(&objects));
+
+        // The objects move towards the center, which leaves nodes above emptied leaves with a single object.
+        // Such a node holding the body itself must not add its softened self-potential.
+        for _ in 0..3 {
+            for (i, object) in objects.iter_mut().enumerate() {
+                object.x += (500. - object.x) * 0.1 * (i % 7) as Scalar;
+                object.y += (500. - object.y) * 0.1 * (i % 5) as Scalar;
+            }
+            assert!(qtree.refit(&objects));
+        }
+
+        for kernel in [SofteningKernel::Plummer, SofteningKernel::Spline] {
+            let softening = Softening::new(kernel, 20.);
+            let exact = get_potential_energy(&objects, softening);
+            let potential = objects.iter().enumerate().map(|(i, object)| qtree.calc_potential(*object, i, 0., softening)).sum::<Scalar>() / 2.;
+
+            assert!((potential - exact).abs() < 1e-5 * exact.abs(), "{kernel:?}: {potential} != {exact}");
+        }
+    }
+
+    #[test]
+    fn test_spatial_queries() {
+        // Every seventh object has no mass, but is found like the others.
+        let objects = objects(500, 1009., 997., |i| if i % 7. == 0. { 0. } else { 1e10 });
+        let qtree = Quadtree::create_from_objects_morton(&objects, Quadtree::calc_tree_box(&objects));
+        let all = || 0..objects.len();
+
+        let (min, max) = (Vec2::new(200., 300.), Vec2::new(500., 450.));
+        let mut in_rect = qtree.bodies_in_rect(min, max);
+        in_rect.sort_unstable();
+        let expected: Vec<usize> = all()
+            .filter(|&i| min.x <= objects[i].x && objects[i].x <= max.x && min.y <= objects[i].y && objects[i].y <= max.y)
+            .collect();
+        assert!(expected.iter().any(|&i| objects[i].m == 0.));
+        assert_eq!(in_rect, expected);
+
+        let center = Vec2::new(480., 520.);
+        let distance = |i: usize| (objects[i].pos() - center).length();
+
+        let mut in_radius = qtree.bodies_in_radius(center, 120.);
+        in_radius.sort_unstable();
+        let expected: Vec<usize> = all().filter(|&i| distance(i) <= 120.).collect();
+        assert!(!expected.is_empty());
+        assert_eq!(in_radius, expected);
+
+        let mut by_distance: Vec<usize> = all().collect();
+        by_distance.sort_by(|&i, &j| distance(i).total_cmp(&distance(j)));
+        for k in [0, 1, 10, 1000] {
+            let nearest = qtree.nearest(center, k);
+            assert_eq!(nearest, by_distance[..k.min(by_distance.len())]);
+        }
+        // Positions outside of the root work as well.
+        assert_eq!(qtree.nearest(Vec2::new(-1e4, 0.), 1).len(), 1);
     }
 }
@@ -1,7 +1,22 @@
 pub mod vec2;
 pub use vec2::Vec2;
 
+pub mod vec3;
+pub use vec3::Vec3;
+
+pub mod particles;
+pub use particles::Particles;
+
+pub mod softening;
+pub use softening::{Softening, SofteningKernel, SPLINE_FACTOR};
+
+/// The floating point type of the simulation.
+/// Positions at astronomical scales need the f64 feature,
+/// Godots Vector2 is only converted to and from at the boundary (see godot_aliases).
+#[cfg(not(feature = "f64"))]
 pub type Scalar = f32;
+#[cfg(feature = "f64")]
+pub type Scalar = f64;
 
 pub const G: Scalar = 6.67430e-11;
 
@@ -24,3 +39,22 @@ impl Object {
         Vec2::new(self.x, self.y)
     }
 }
+
+/// The 3D counterpart of Object.
+#[derive(Debug, Clone, Copy)]
+pub struct Object3D {
+    pub x: Scalar,
+    pub y: Scalar,
+    pub z: Scalar,
+    pub m: Scalar,
+}
+
+impl Object3D {
+    pub fn new(x: Scalar, y: Scalar, z: Scalar, m: Scalar) -> Self {
+        Self { x, y, z, m }
+    }
+
+    pub fn pos(self) -> Vec3 {
+        Vec3::new(self.x, self.y, self.z)
+    }
+}
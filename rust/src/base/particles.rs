@@ -0,0 +1,55 @@
+use crate::base::{Scalar, Object};
+
+/// Objects in a structure of arrays layout,
+/// so the force kernels can load the same coordinate of several objects at once (see kernel).
+#[derive(Debug, Clone, Default)]
+pub struct Particles {
+    pub x: Vec<Scalar>,
+    pub y: Vec<Scalar>,
+    pub m: Vec<Scalar>,
+}
+
+impl Particles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_objects(objects: &[Object]) -> Self {
+        let mut particles = Self::new();
+        particles.extend(objects.iter().copied());
+        particles
+    }
+
+    pub fn push(&mut self, object: Object) {
+        self.x.push(object.x);
+        self.y.push(object.y);
+        self.m.push(object.m);
+    }
+
+    /// Removes all particles, but keeps the allocated memory.
+    pub fn clear(&mut self) {
+        self.x.clear();
+        self.y.clear();
+        self.m.clear();
+    }
+
+    pub fn get(&self, i: usize) -> Object {
+        Object::new(self.x[i], self.y[i], self.m[i])
+    }
+
+    pub fn len(&self) -> usize {
+        self.m.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.m.is_empty()
+    }
+}
+
+impl Extend<Object> for Particles {
+    fn extend<T: IntoIterator<Item = Object>>(&mut self, objects: T) {
+        for object in objects {
+            self.push(object);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use crate::base::{Scalar, Object, Object3D, Vec2, Vec3, G};
+
+/// The Gadget spline kernel reaches the Newtonian force at h = SPLINE_FACTOR * ε,
+/// which makes the potential at r = 0 equal to the one of a Plummer sphere with the length ε.
+pub const SPLINE_FACTOR: Scalar = 2.8;
+
+/// The kernels that soften the gravitational force at close range.
+/// With the godot feature it can be exported as a property.
+#[cfg_attr(feature = "godot", derive(godot::prelude::Property, godot::prelude::Export))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(i64)]
+pub enum SofteningKernel {
+    /// The unsoftened 1 / r² force.
+    #[default]
+    None = 0,
+    /// The force of a Plummer sphere: r / (r² + ε²)^(3/2).
+    Plummer = 1,
+    /// The cubic spline kernel of Monaghan, as used by Gadget.
+    /// It is exactly Newtonian beyond SPLINE_FACTOR * ε.
+    Spline = 2,
+}
+
+/// A softening kernel with its softening length ε.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Softening {
+    pub kernel: SofteningKernel,
+    pub length: Scalar,
+}
+
+impl Softening {
+    pub fn new(kernel: SofteningKernel, length: Scalar) -> Self {
+        Self { kernel, length }
+    }
+
+    /// Returns the same softening for coordinates that are scaled by 1 / scale.
+    pub fn scaled(&self, scale: Scalar) -> Self {
+        Self::new(self.kernel, self.length / scale)
+    }
+
+    /// Returns K(r), so the force between two masses is G * m1 * m2 * K(r) * r_vec.
+    /// The unsoftened K(r) is 1 / r³.
+    pub fn factor(&self, r: Scalar) -> Scalar {
+        match self.kernel {
+            SofteningKernel::None => 1. / (r * r * r),
+            SofteningKernel::Plummer => {
+                let r_sqr = r * r + self.length * self.length;
+                1. / (r_sqr * r_sqr.sqrt())
+            }
+            SofteningKernel::Spline => {
+                let h = SPLINE_FACTOR * self.length;
+                if r >= h { return 1. / (r * r * r) }
+
+                let u = r / h;
+                let h3_inv = 1. / (h * h * h);
+                if u < 0.5 {
+                    h3_inv * (32. / 3. + u * u * (32. * u - 38.4))
+                } else {
+                    h3_inv * (64. / 3. - 48. * u + 38.4 * u * u
+                        - 32. / 3. * u * u * u - 1. / 15. / (u * u * u))
+                }
+            }
+        }
+    }
+
+    /// Returns the derivative dK/dr of the factor, which is needed for the jerk.
+    pub fn factor_derivative(&self, r: Scalar) -> Scalar {
+        match self.kernel {
+            SofteningKernel::None => -3. / (r * r * r * r),
+            SofteningKernel::Plummer => {
+                let r_sqr = r * r + self.length * self.length;
+                -3. * r / (r_sqr * r_sqr * r_sqr.sqrt())
+            }
+            SofteningKernel::Spline => {
+                let h = SPLINE_FACTOR * self.length;
+                if r >= h { return -3. / (r * r * r * r) }
+
+                let u = r / h;
+                let h4_inv = 1. / (h * h * h * h);
+                if u < 0.5 {
+                    h4_inv * (-76.8 * u + 96. * u * u)
+                } else {
+                    h4_inv * (-48. + 76.8 * u - 32. * u * u + 0.2 / (u * u * u * u))
+                }
+            }
+        }
+    }
+
+    /// Returns P(r), so the potential energy of two masses is -G * m1 * m2 * P(r).
+    /// The unsoftened P(r) is 1 / r, its derivative is -K(r) * r.
+    pub fn potential_factor(&self, r: Scalar) -> Scalar {
+        match self.kernel {
+            SofteningKernel::None => 1. / r,
+            SofteningKernel::Plummer => 1. / (r * r + self.length * self.length).sqrt(),
+            SofteningKernel::Spline => {
+                let h = SPLINE_FACTOR * self.length;
+                if r >= h { return 1. / r }
+
+                let u = r / h;
+                if u < 0.5 {
+                    (2.8 - u * u * (16. / 3. - u * u * (9.6 - 6.4 * u))) / h
+                } else {
+                    (3.2 - 1. / 15. / u - u * u * (32. / 3. - u * (16. - u * (9.6 - 32. / 15. * u)))) / h
+                }
+            }
+        }
+    }
+
+    /// Returns the potential energy of the two objects.
+    /// The softened potential is finite at r = 0, so distinct objects at the same position have one.
+    /// Without softening it is singular there and the objects do not have one.
+    /// The callers skip an object itself by its index.
+    pub fn potential(&self, obj1: Object, obj2: Object) -> Scalar {
+        let r = (obj2.pos() - obj1.pos()).length();
+        if r == 0. && self.kernel == SofteningKernel::None { return 0. }
+
+        -G * obj1.m * obj2.m * self.potential_factor(r)
+    }
+
+    /// Returns the force obj2 exerts on obj1.
+    /// Objects at the same position do not exert a force on each other.
+    pub fn force(&self, obj1: Object, obj2: Object) -> Vec2 {
+        let r_vec = obj2.pos() - obj1.pos();
+        let r = r_vec.length();
+        if r == 0. { return Vec2::new(0., 0.) }
+
+        G * (obj1.m * obj2.m * self.factor(r)) * r_vec
+    }
+
+    /// The 3D counterpart of force.
+    pub fn force_3d(&self, obj1: Object3D, obj2: Object3D) -> Vec3 {
+        let r_vec = obj2.pos() - obj1.pos();
+        let r = r_vec.length();
+        if r == 0. { return Vec3::new(0., 0., 0.) }
+
+        G * (obj1.m * obj2.m * self.factor(r)) * r_vec
+    }
+
+    /// Returns the force obj2 exerts on obj1 and its time derivative, the jerk.
+    /// v_vec is the velocity of obj2 relative to obj1.
+    pub fn force_and_jerk(&self, obj1: Object, obj2: Object, v_vec: Vec2) -> (Vec2, Vec2) {
+        let r_vec = obj2.pos() - obj1.pos();
+        let r = r_vec.length();
+        if r == 0. { return (Vec2::new(0., 0.), Vec2::new(0., 0.)) }
+
+        let g_m = G * obj1.m * obj2.m;
+        let force = g_m * self.factor(r) * r_vec;
+        // d/dt (K(r) * r_vec) = K(r) * v_vec + dK/dr * (r_vec · v_vec) / r * r_vec
+        let jerk = g_m * (self.factor(r) * v_vec + (self.factor_derivative(r) * r_vec.dot(v_vec) / r) * r_vec);
+        (force, jerk)
+    }
+}
+
+#[cfg(test)]
+mod softening_tests {
+    use crate::base::*;
+
+    #[test]
+    fn test_kernels_are_newtonian_at_large_distances() {
+        for kernel in [SofteningKernel::Plummer, SofteningKernel::Spline] {
+            let softening = Softening::new(kernel, 1.);
+            let newtonian = 1. / (100. as Scalar).powi(3);
+            assert!((softening.factor(100.) - newtonian).abs() < 1e-3 * newtonian);
+        }
+    }
+
+    #[test]
+    fn test_kernels_stay_finite_at_close_range() {
+        for kernel in [SofteningKernel::Plummer, SofteningKernel::Spline] {
+            let softening = Softening::new(kernel, 1.);
+            let force = softening.force(Object::new(0., 0., 1e10), Object::new(1e-6, 0., 1e10));
+            assert!(force.length() < G * 1e20);
+        }
+    }
+
+    #[test]
+    fn test_spline_is_continuous() {
+        let softening = Softening::new(SofteningKernel::Spline, 1.);
+        let h = SPLINE_FACTOR;
+        for r in [0.5 * h, h] {
+            let (below, above) = (softening.factor(r - 1e-4), softening.factor(r + 1e-4));
+            assert!((below - above).abs() < 1e-2 * above);
+        }
+    }
+
+    #[test]
+    fn test_factor_derivative_matches_finite_difference() {
+        for kernel in [SofteningKernel::None, SofteningKernel::Plummer, SofteningKernel::Spline] {
+            let softening = Softening::new(kernel, 1.);
+            for r in [0.5, 2., 5.] {
+                let step = 1e-3;
+                let finite_difference = (softening.factor(r + step) - softening.factor(r - step)) / (2. * step);
+                let derivative = softening.factor_derivative(r);
+                assert!((finite_difference - derivative).abs() < 1e-2 * derivative.abs().max(1e-3), "{kernel:?} at {r}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_potential_factor_matches_the_force() {
+        for kernel in [SofteningKernel::None, SofteningKernel::Plummer, SofteningKernel::Spline] {
+            let softening = Softening::new(kernel, 1.);
+            // Both branches of the spline and beyond it.
+            for r in [0.5, 2., 5.] {
+                let step = 1e-3;
+                let finite_difference = (softening.potential_factor(r + step) - softening.potential_factor(r - step)) / (2. * step);
+                let expected = -softening.factor(r) * r;
+                assert!((finite_difference - expected).abs() < 1e-2 * expected.abs(), "{kernel:?} at {r}");
+            }
+        }
+        // The spline matches the Plummer potential at r = 0.
+        let (spline, plummer) = (Softening::new(SofteningKernel::Spline, 1.), Softening::new(SofteningKernel::Plummer, 1.));
+        assert!((spline.potential_factor(0.) - plummer.potential_factor(0.)).abs() < 1e-5);
+    }
+}
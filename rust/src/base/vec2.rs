@@ -2,7 +2,7 @@ use std::ops;
 
 use crate::base::Scalar;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct Vec2 {
     pub x: Scalar,
     pub y: Scalar,
@@ -17,6 +17,15 @@ impl Vec2 {
         (self.x.powi(2) + self.y.powi(2)).sqrt()
     }
 
+    pub fn dot(&self, vec2: Vec2) -> Scalar {
+        self.x * vec2.x + self.y * vec2.y
+    }
+
+    /// Returns the z component of the cross product of the vectors extended to 3D.
+    pub fn cross(&self, vec2: Vec2) -> Scalar {
+        self.x * vec2.y - self.y * vec2.x
+    }
+
     pub fn normalize(&self) -> Vec2 {
         Vec2::new(self.x / self.length(), self.y / self.length())
     }
@@ -44,7 +53,7 @@ impl ops::Sub<Self> for Vec2 {
 
 impl ops::SubAssign<Self> for Vec2 {
     fn sub_assign(&mut self, vec2: Vec2) {
-        self.x =- vec2.x; self.y =- vec2.y;
+        self.x -= vec2.x; self.y -= vec2.y;
     }
 }
 
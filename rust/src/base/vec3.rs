@@ -0,0 +1,102 @@
+use std::ops;
+
+use crate::base::Scalar;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Vec3 {
+    pub x: Scalar,
+    pub y: Scalar,
+    pub z: Scalar,
+}
+
+impl Vec3 {
+    pub fn new(x: Scalar, y: Scalar, z: Scalar) -> Self {
+        Self {x, y, z}
+    }
+
+    pub fn length(&self) -> Scalar {
+        (self.x.powi(2) + self.y.powi(2) + self.z.powi(2)).sqrt()
+    }
+
+    pub fn dot(&self, vec3: Vec3) -> Scalar {
+        self.x * vec3.x + self.y * vec3.y + self.z * vec3.z
+    }
+
+    pub fn normalize(&self) -> Vec3 {
+        Vec3::new(self.x / self.length(), self.y / self.length(), self.z / self.length())
+    }
+}
+
+impl ops::Add<Self> for Vec3 {
+    type Output = Self;
+    fn add(self, vec3: Vec3) -> Self::Output {
+        Vec3::new(self.x + vec3.x, self.y + vec3.y, self.z + vec3.z)
+    }
+}
+
+impl ops::AddAssign<Self> for Vec3 {
+    fn add_assign(&mut self, vec3: Vec3) {
+        self.x += vec3.x; self.y += vec3.y; self.z += vec3.z;
+    }
+}
+
+impl ops::Sub<Self> for Vec3 {
+    type Output = Self;
+    fn sub(self, vec3: Vec3) -> Self::Output {
+        Vec3::new(self.x - vec3.x, self.y - vec3.y, self.z - vec3.z)
+    }
+}
+
+impl ops::SubAssign<Self> for Vec3 {
+    fn sub_assign(&mut self, vec3: Vec3) {
+        self.x -= vec3.x; self.y -= vec3.y; self.z -= vec3.z;
+    }
+}
+
+impl ops::Neg for Vec3 {
+    type Output = Self;
+    fn neg(self) -> Self::Output {
+        Vec3::new(-self.x, -self.y, -self.z)
+    }
+}
+
+impl ops::Mul<Scalar> for Vec3 {
+    type Output = Self;
+    fn mul(self, scalar: Scalar) -> Self::Output {
+        Vec3::new(self.x * scalar, self.y * scalar, self.z * scalar)
+    }
+}
+
+impl ops::Mul<Vec3> for Scalar {
+    type Output = Vec3;
+    fn mul(self, vec3: Vec3) -> Self::Output {
+        Vec3::new(self * vec3.x, self * vec3.y, self * vec3.z)
+    }
+}
+
+impl ops::MulAssign<Scalar> for Vec3 {
+    fn mul_assign(&mut self, scalar: Scalar) {
+        self.x *= scalar; self.y *= scalar; self.z *= scalar;
+    }
+}
+
+impl ops::Div<Scalar> for Vec3 {
+    type Output = Self;
+    fn div(self, scalar: Scalar) -> Self::Output {
+        Vec3::new(self.x / scalar, self.y / scalar, self.z / scalar)
+    }
+}
+
+impl ops::DivAssign<Scalar> for Vec3 {
+    fn div_assign(&mut self, scalar: Scalar) {
+        self.x /= scalar; self.y /= scalar; self.z /= scalar;
+    }
+}
+
+impl core::cmp::PartialEq<Vec3> for Vec3 {
+    fn eq(&self, vec3: &Vec3) -> bool {
+        self.x == vec3.x && self.y == vec3.y && self.z == vec3.z
+    }
+}
+
+impl Eq for Vec3 {}
@@ -0,0 +1,60 @@
+use crate::base::{Scalar, Vec2};
+
+/// How the simulation handles bodies that overlap.
+/// With the godot feature it can be exported as a property.
+#[cfg_attr(feature = "godot", derive(godot::prelude::Property, godot::prelude::Export))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(i64)]
+pub enum Collisions {
+    /// The bodies pass through each other.
+    #[default]
+    Ignore = 0,
+    /// The lighter body is absorbed by the heavier one, see Simulation::resolve_collisions.
+    Merge = 1,
+    /// The bodies bounce off each other elastically, see Simulation::resolve_collisions.
+    Bounce = 2,
+    /// The bodies pass through each other, but the collisions are still detected.
+    Report = 3,
+}
+
+/// Describes two bodies that were merged into one.
+/// The indices are the ones of the bodies before the absorbed bodies were removed.
+#[derive(Debug, Clone, Copy)]
+pub struct Merger {
+    /// The heavier body, which now has the merged state.
+    pub survivor: usize,
+    /// The lighter body, which was removed.
+    pub absorbed: usize,
+    /// The center of mass of the two bodies.
+    pub position: Vec2,
+    /// The velocity that conserves the linear momentum.
+    pub velocity: Vec2,
+    pub mass: Scalar,
+    /// The radius of a circle with the area of both bodies.
+    pub radius: Scalar,
+    /// The angular momentum of the merged body around its center,
+    /// which includes the angular momentum of the two bodies around their center of mass.
+    pub spin: Scalar,
+}
+
+/// Checks if the circles of two bodies overlap.
+/// Bodies without a radius never overlap.
+pub fn overlap(pos1: Vec2, radius1: Scalar, pos2: Vec2, radius2: Scalar) -> bool {
+    let radii = radius1 + radius2;
+    radii > 0. && (pos2 - pos1).length() < radii
+}
+
+/// Returns every pair (i, j) with i < j of bodies that overlap, by checking all pairs.
+/// The quadtree finds them faster, see Quadtree::overlapping_pairs.
+pub fn overlapping_pairs(positions: &[Vec2], radii: &[Scalar]) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+
+    for i in 0..positions.len() {
+        for j in i + 1..positions.len() {
+            if overlap(positions[i], radii[i], positions[j], radii[j]) {
+                pairs.push((i, j));
+            }
+        }
+    }
+    pairs
+}
@@ -0,0 +1,64 @@
+use crate::base::{Scalar, Vec2};
+
+/// Above this number of bodies the potential energy is calculated with a quadtree in O(n log(n))
+/// instead of summing up every pair.
+pub const DIRECT_POTENTIAL_CUTOFF: usize = 1024;
+
+/// The accuracy θ of the potential energy that is calculated with a quadtree.
+pub const POTENTIAL_THETA: Scalar = 0.5;
+
+/// The quantities a closed system of bodies conserves, which show if a simulation is physically sane.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Diagnostics {
+    pub kinetic_energy: Scalar,
+    pub potential_energy: Scalar,
+    pub momentum: Vec2,
+    /// The angular momentum around the origin, including the spins of the bodies.
+    pub angular_momentum: Scalar,
+    pub center_of_mass: Vec2,
+    pub mass: Scalar,
+}
+
+/// The relative change of the conserved quantities since a reference, see Diagnostics::drift.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Drift {
+    pub energy: Scalar,
+    pub momentum: Scalar,
+    pub angular_momentum: Scalar,
+}
+
+impl Diagnostics {
+    /// Calculates the diagnostics of the bodies, the potential energy has to be calculated beforehand.
+    pub fn new(positions: &[Vec2], velocities: &[Vec2], masses: &[Scalar], spins: &[Scalar], potential_energy: Scalar) -> Self {
+        let mut diagnostics = Self { potential_energy, ..Default::default() };
+        let mut weighted_position = Vec2::new(0., 0.);
+
+        for (((pos, vel), m), spin) in positions.iter().zip(velocities).zip(masses).zip(spins) {
+            diagnostics.kinetic_energy += 0.5 * m * vel.dot(*vel);
+            diagnostics.momentum += *vel * *m;
+            diagnostics.angular_momentum += m * pos.cross(*vel) + spin;
+            diagnostics.mass += m;
+            weighted_position += *pos * *m;
+        }
+        if diagnostics.mass != 0. {
+            diagnostics.center_of_mass = weighted_position / diagnostics.mass;
+        }
+        diagnostics
+    }
+
+    pub fn total_energy(&self) -> Scalar {
+        self.kinetic_energy + self.potential_energy
+    }
+
+    /// Returns the change since the reference relative to the magnitude of the reference.
+    /// If the reference is zero, which is common for the momentum, the absolute change is returned.
+    pub fn drift(&self, reference: &Diagnostics) -> Drift {
+        let relative = |change: Scalar, magnitude: Scalar| if magnitude == 0. { change } else { change / magnitude };
+
+        Drift {
+            energy: relative((self.total_energy() - reference.total_energy()).abs(), reference.total_energy().abs()),
+            momentum: relative((self.momentum - reference.momentum).length(), reference.momentum.length()),
+            angular_momentum: relative((self.angular_momentum - reference.angular_momentum).abs(), reference.angular_momentum.abs()),
+        }
+    }
+}
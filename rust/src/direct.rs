@@ -1,30 +1,237 @@
-use crate::base::{Object, Vec2, G};
+use crate::base::{Scalar, Object, Object3D, Vec2, Vec3, Softening, Particles};
+use crate::kernel;
+use crate::solver::{GravitySolver, GravitySolver3D};
 
-pub fn get_forces(objects: &Vec<Object>) -> Vec<Vec2> {
-    let mut forces = Vec::<Vec2>::new();
+/// The number of objects of a tile, see get_forces_tiled.
+pub const TILE_SIZE: usize = 256;
 
-    for obj1 in objects {
-        for obj2 in objects {
-            if obj1.x == obj2.y && obj1.x == obj2.y { continue; }
+/// The direct approach, which sums up the forces between every pair of objects in O(n²).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Direct {
+    pub softening: Softening,
+    /// Uses get_forces_simd instead of visiting every pair only once.
+    pub simd: bool,
+}
+
+impl Direct {
+    pub fn new(softening: Softening) -> Self {
+        Self { softening, simd: false }
+    }
+}
+
+impl GravitySolver for Direct {
+    fn calc_forces(&mut self, objects: &[Object]) -> Vec<Vec2> {
+        if self.simd { return get_forces_simd(objects, self.softening) }
 
-            let r_vec = obj1.pos() - obj2.pos();
-            let force = G * ((obj1.m * obj2.m) / r_vec.length().powf(3.)) * r_vec;
-            forces.push(force);
+        // With only a few tiles, the tiling does not pay off.
+        if objects.len() > 4 * TILE_SIZE {
+            get_forces_tiled(objects, self.softening)
+        } else {
+            get_forces(objects, self.softening)
         }
     }
+
+    fn calc_forces_on(&mut self, objects: &[Object], targets: &[usize]) -> Vec<Vec2> {
+        targets.iter().map(|&i| get_force(objects, i, self.softening)).collect()
+    }
+}
+
+impl GravitySolver3D for Direct {
+    fn calc_forces_3d(&mut self, objects: &[Object3D]) -> Vec<Vec3> {
+        get_forces_3d(objects, self.softening)
+    }
+}
+
+/// Returns the total force that acts on the object i.
+pub fn get_force(objects: &[Object], i: usize, softening: Softening) -> Vec2 {
+    let mut total_force = Vec2::new(0., 0.);
+
+    for (j, obj2) in objects.iter().enumerate() {
+        if i == j { continue; }
+
+        total_force += softening.force(objects[i], *obj2);
+    }
+    total_force
+}
+
+/// Returns the total force that acts on every object.
+pub fn get_forces(objects: &[Object], softening: Softening) -> Vec<Vec2> {
+    let mut forces = vec![Vec2::new(0., 0.); objects.len()];
+
+    apply_forces(objects, softening, |i, force| {
+        forces[i] += force;
+    });
     forces
 }
 
-pub fn apply_forces(objects: &Vec<Object>, mut f: impl FnMut(usize, Vec2)) {
+/// Calls f(i, force) for the force every other object exerts on the object i.
+/// Every pair of objects is only visited once,
+/// because the objects exert equal and opposite forces onto each other (Newton's third law).
+pub fn apply_forces(objects: &[Object], softening: Softening, mut f: impl FnMut(usize, Vec2)) {
     for (i, obj1) in objects.iter().enumerate() {
-        for obj2 in objects {
-            if obj1.x == obj2.y && obj1.x == obj2.y { continue; }
-
-            let r_vec = obj1.pos() - obj2.pos();
-            let force = G * ((obj1.m * obj2.m) / r_vec.length().powf(3.)) * r_vec;
+        for (j, obj2) in objects.iter().enumerate().skip(i + 1) {
+            let force = softening.force(*obj1, *obj2);
 
             f(i, force);
+            f(j, -force);
         }
     }
+}
+
+/// Returns the total force that acts on every object, like get_forces.
+/// The pairs are visited in tiles of TILE_SIZE x TILE_SIZE objects,
+/// so the objects and forces of both tiles stay in the cache for large numbers of objects.
+pub fn get_forces_tiled(objects: &[Object], softening: Softening) -> Vec<Vec2> {
+    let mut forces = vec![Vec2::new(0., 0.); objects.len()];
+
+    for start1 in (0..objects.len()).step_by(TILE_SIZE) {
+        let end1 = (start1 + TILE_SIZE).min(objects.len());
+
+        // Only the tiles on and above the diagonal are visited.
+        for start2 in (start1..objects.len()).step_by(TILE_SIZE) {
+            let end2 = (start2 + TILE_SIZE).min(objects.len());
+
+            for i in start1..end1 {
+                // Inside of the tiles on the diagonal every pair is only visited once as well.
+                for j in start2.max(i + 1)..end2 {
+                    let force = softening.force(objects[i], objects[j]);
+
+                    forces[i] += force;
+                    forces[j] -= force;
+                }
+            }
+        }
+    }
+    forces
+}
+
+/// Returns the total force and its time derivative, the jerk, for every object.
+/// The velocities need to be in the same order as the objects.
+pub fn get_forces_and_jerks(objects: &[Object], velocities: &[Vec2], softening: Softening) -> (Vec<Vec2>, Vec<Vec2>) {
+    let mut forces = vec![Vec2::new(0., 0.); objects.len()];
+    let mut jerks = vec![Vec2::new(0., 0.); objects.len()];
 
+    apply_forces_and_jerks(objects, velocities, softening, |i, force, jerk| {
+        forces[i] += force;
+        jerks[i] += jerk;
+    });
+    (forces, jerks)
+}
+
+/// Calls f(i, force, jerk) for the force and the jerk every other object exerts on the object i.
+/// The jerk is the time derivative of the force, see Softening::force_and_jerk.
+/// Like the forces, the jerks of a pair are equal and opposite, so every pair is only visited once.
+pub fn apply_forces_and_jerks(objects: &[Object], velocities: &[Vec2], softening: Softening, mut f: impl FnMut(usize, Vec2, Vec2)) {
+    for (i, obj1) in objects.iter().enumerate() {
+        for (j, obj2) in objects.iter().enumerate().skip(i + 1) {
+            let v_vec = velocities[j] - velocities[i];
+            let (force, jerk) = softening.force_and_jerk(*obj1, *obj2, v_vec);
+
+            f(i, force, jerk);
+            f(j, -force, -jerk);
+        }
+    }
+}
+
+/// Returns the total force that acts on every object, like get_forces.
+/// Every pair is visited twice, but the objects are stored as Particles
+/// and the forces onto an object are summed up by the vectorized kernel.
+pub fn get_forces_simd(objects: &[Object], softening: Softening) -> Vec<Vec2> {
+    let particles = Particles::from_objects(objects);
+
+    objects.iter().enumerate().map(|(i, object)| kernel::force_without(*object, i, &particles, softening)).collect()
+}
+
+/// Returns the potential energy of all pairs of objects.
+pub fn get_potential_energy(objects: &[Object], softening: Softening) -> Scalar {
+    let mut energy = 0.;
+
+    for (i, obj1) in objects.iter().enumerate() {
+        for obj2 in &objects[i + 1..] {
+            energy += softening.potential(*obj1, *obj2);
+        }
+    }
+    energy
+}
+
+/// The 3D counterpart of get_forces.
+pub fn get_forces_3d(objects: &[Object3D], softening: Softening) -> Vec<Vec3> {
+    let mut forces = vec![Vec3::new(0., 0., 0.); objects.len()];
+
+    for (i, obj1) in objects.iter().enumerate() {
+        for (j, obj2) in objects.iter().enumerate().skip(i + 1) {
+            let force = softening.force_3d(*obj1, *obj2);
+
+            forces[i] += force;
+            forces[j] -= force;
+        }
+    }
+    forces
+}
+
+#[cfg(test)]
+mod direct_tests {
+    use crate::base::*;
+    use crate::direct::*;
+    use crate::solver::GravitySolver;
+    use crate::tests::objects;
+
+    #[test]
+    fn test_symmetric_forces_match_every_pair() {
+        let objects = objects(300, 1009., 997., |i| 1e10 + i);
+        let softening = Softening::new(SofteningKernel::Plummer, 5.);
+
+        let forces = get_forces(&objects, softening);
+        for (i, force) in forces.iter().enumerate() {
+            let expected = get_force(&objects, i, softening);
+            assert!((*force - expected).length() <= 1e-3 * expected.length());
+        }
+    }
+
+    #[test]
+    fn test_tiled_forces_match() {
+        // Not a multiple of the tile size, so the last tiles are smaller.
+        let objects = objects(3 * TILE_SIZE + 17, 1009., 997., |i| 1e10 + i);
+        let softening = Softening::default();
+
+        let forces = get_forces(&objects, softening);
+        let tiled_forces = get_forces_tiled(&objects, softening);
+        for (force, tiled_force) in forces.iter().zip(&tiled_forces) {
+            assert!((*force - *tiled_force).length() <= 1e-3 * force.length());
+        }
+    }
+
+    #[test]
+    fn test_simd_forces_match_for_coincident_objects() {
+        // Distinct objects at the same position.
+        let mut objects = objects(100, 101., 97., |i| 1e10 + i);
+        objects.push(objects[3]);
+        objects.push(objects[3]);
+
+        for kernel in [SofteningKernel::None, SofteningKernel::Plummer, SofteningKernel::Spline] {
+            let softening = Softening::new(kernel, 5.);
+
+            let forces = Direct::new(softening).calc_forces(&objects);
+            let simd_forces = Direct { simd: true, ..Direct::new(softening) }.calc_forces(&objects);
+            for (force, simd_force) in forces.iter().zip(&simd_forces) {
+                assert!(simd_force.x.is_finite() && simd_force.y.is_finite());
+                assert!((*force - *simd_force).length() <= 1e-3 * force.length());
+            }
+        }
+    }
+
+    #[test]
+    fn test_simd_forces_match() {
+        let objects = objects(1000, 1009., 997., |i| 1e10 + i);
+
+        for kernel in [SofteningKernel::None, SofteningKernel::Plummer, SofteningKernel::Spline] {
+            let softening = Softening::new(kernel, 5.);
+
+            let forces = get_forces(&objects, softening);
+            let simd_forces = get_forces_simd(&objects, softening);
+            for (force, simd_force) in forces.iter().zip(&simd_forces) {
+                assert!((*force - *simd_force).length() <= 1e-3 * force.length());
+            }
+        }
+    }
 }
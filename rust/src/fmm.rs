@@ -0,0 +1,55 @@
+pub mod complex;
+pub mod expansion;
+pub mod fmm_tree;
+
+pub use complex::Complex;
+pub use expansion::Expansions;
+pub use fmm_tree::FmmTree;
+
+pub use crate::base::{Object, Scalar, Vec2, Softening};
+use crate::solver::GravitySolver;
+
+/// The default number of terms per index of the expansions.
+pub const ORDER: usize = 8;
+
+/// The default separation criterion.
+/// Two cells are well separated if (radius_a + radius_b) / distance < θ.
+pub const THETA: Scalar = 0.5;
+
+/// The maximum number of bodies in a leaf.
+pub const LEAF_CAPACITY: usize = 16;
+
+/// Stops splitting cells with coincident bodies.
+pub const MAX_DEPTH: usize = 32;
+
+/// The Fast-Multipole-Method with O(n).
+#[derive(Debug, Clone, Copy)]
+pub struct Fmm {
+    /// The number of terms per index of the expansions.
+    pub order: usize,
+    /// The separation criterion, see THETA.
+    pub theta: Scalar,
+    /// Only applied to the direct interactions of neighbouring bodies,
+    /// the expansions always use the unsoftened force.
+    pub softening: Softening,
+}
+
+impl Fmm {
+    pub fn new(order: usize, theta: Scalar) -> Self {
+        Self { order, theta, softening: Softening::default() }
+    }
+}
+
+impl Default for Fmm {
+    fn default() -> Self {
+        Self::new(ORDER, THETA)
+    }
+}
+
+impl GravitySolver for Fmm {
+    fn calc_forces(&mut self, objects: &[Object]) -> Vec<Vec2> {
+        FmmTree::create_from_objects(objects, self.order, self.theta)
+            .with_softening(self.softening)
+            .calc_forces()
+    }
+}
@@ -0,0 +1,94 @@
+use std::ops;
+
+/// A complex number used by the multipole expansions.
+/// It always uses f64, because the expansions contain high powers of the distances,
+/// which quickly leave the range and precision of f32.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub const ZERO: Self = Self { re: 0., im: 0. };
+    pub const ONE: Self = Self { re: 1., im: 0. };
+
+    pub fn new(re: f64, im: f64) -> Self {
+        Self { re, im }
+    }
+
+    pub fn conj(&self) -> Self {
+        Self::new(self.re, -self.im)
+    }
+
+    /// Returns the absolute value |z|.
+    pub fn abs(&self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    /// Returns the squared absolute value |z|².
+    pub fn norm_sqr(&self) -> f64 {
+        self.re * self.re + self.im * self.im
+    }
+
+    /// Returns 1 / z.
+    pub fn inv(&self) -> Self {
+        let norm_sqr = self.norm_sqr();
+        Self::new(self.re / norm_sqr, -self.im / norm_sqr)
+    }
+
+    /// Returns [1, z, z², ..., z^(n-1)].
+    pub fn powers(&self, n: usize) -> Vec<Self> {
+        let mut powers = Vec::with_capacity(n);
+        let mut power = Self::ONE;
+        for _ in 0..n {
+            powers.push(power);
+            power = power * *self;
+        }
+        powers
+    }
+}
+
+impl ops::Add<Self> for Complex {
+    type Output = Self;
+    fn add(self, z: Complex) -> Self::Output {
+        Complex::new(self.re + z.re, self.im + z.im)
+    }
+}
+
+impl ops::AddAssign<Self> for Complex {
+    fn add_assign(&mut self, z: Complex) {
+        self.re += z.re; self.im += z.im;
+    }
+}
+
+impl ops::Sub<Self> for Complex {
+    type Output = Self;
+    fn sub(self, z: Complex) -> Self::Output {
+        Complex::new(self.re - z.re, self.im - z.im)
+    }
+}
+
+impl ops::Neg for Complex {
+    type Output = Self;
+    fn neg(self) -> Self::Output {
+        Complex::new(-self.re, -self.im)
+    }
+}
+
+impl ops::Mul<Self> for Complex {
+    type Output = Self;
+    fn mul(self, z: Complex) -> Self::Output {
+        Complex::new(
+            self.re * z.re - self.im * z.im,
+            self.re * z.im + self.im * z.re,
+        )
+    }
+}
+
+impl ops::Mul<f64> for Complex {
+    type Output = Self;
+    fn mul(self, scalar: f64) -> Self::Output {
+        Complex::new(self.re * scalar, self.im * scalar)
+    }
+}
@@ -0,0 +1,156 @@
+use crate::fmm::Complex;
+
+/// The translation operators for multipole and local expansions of a fixed order.
+///
+/// The potential Φ(z) = Σ m / |z - z_i| is split into the analytic and
+/// the anti-analytic part: 1 / |w| = w^(-1/2) * conj(w)^(-1/2).
+/// Because of this, an expansion has two indices and is stored row-major as order * order coefficients:
+/// - a multipole expansion stores the moments M_ab = Σ m * s^a * conj(s)^b,
+///   with s being the position of a body relative to the expansions center.
+/// - a local expansion stores the coefficients L_jk of Φ(ζ) = Σ L_jk * ζ^j * conj(ζ)^k,
+///   with ζ being the position relative to the expansions center.
+///
+/// All translations have the form T * X * T^H, so they cost O(order³).
+#[derive(Debug, Clone)]
+pub struct Expansions {
+    order: usize,
+    /// binomials[n][k] = n choose k, for n < 2 * order.
+    binomials: Vec<Vec<f64>>,
+    /// The coefficients of the series (1 + u)^(-1/2) = Σ g_n * u^n, for n < 2 * order.
+    g: Vec<f64>,
+}
+
+impl Expansions {
+    pub fn new(order: usize) -> Self {
+        let order = order.max(1);
+        let max_n = 2 * order;
+
+        let mut binomials = vec![vec![1.; 1]; max_n];
+        for n in 1..max_n {
+            let mut row = vec![1.; n + 1];
+            for k in 1..n {
+                row[k] = binomials[n - 1][k - 1] + binomials[n - 1][k];
+            }
+            binomials[n] = row;
+        }
+
+        let mut g = vec![1.; max_n];
+        for n in 1..max_n {
+            g[n] = g[n - 1] * (-0.5 - (n - 1) as f64) / n as f64;
+        }
+
+        Self { order, binomials, g }
+    }
+
+    /// Returns the number of terms per index.
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    /// Returns the number of coefficients of a single expansion.
+    pub fn size(&self) -> usize {
+        self.order * self.order
+    }
+
+    /// Adds a body with the mass m at the relative position s to the multipole expansion.
+    pub fn p2m(&self, s: Complex, m: f64, multipole: &mut [Complex]) {
+        let p = self.order;
+        let powers = s.powers(p);
+
+        for a in 0..p {
+            let m_s_a = powers[a] * m;
+            for b in 0..p {
+                multipole[a * p + b] += m_s_a * powers[b].conj();
+            }
+        }
+    }
+
+    /// Shifts the multipole expansion of a child to its parent and adds it there.
+    /// d is the childs center relative to the parents center.
+    pub fn m2m(&self, child: &[Complex], d: Complex, parent: &mut [Complex]) {
+        let p = self.order;
+        let powers = d.powers(p);
+
+        let mut t = vec![Complex::ZERO; p * p];
+        for k in 0..p {
+            for a in 0..=k {
+                t[k * p + a] = powers[k - a] * self.binomials[k][a];
+            }
+        }
+        self.sandwich_add(&t, child, 1., parent);
+    }
+
+    /// Converts the multipole expansion of a well separated cell into a local expansion and adds it.
+    /// d is the local expansions center relative to the multipole expansions center.
+    pub fn m2l(&self, multipole: &[Complex], d: Complex, local: &mut [Complex]) {
+        let p = self.order;
+        let powers = d.inv().powers(2 * p - 1);
+
+        let mut t = vec![Complex::ZERO; p * p];
+        for j in 0..p {
+            for a in 0..p {
+                let sign = if a % 2 == 0 { 1. } else { -1. };
+                let factor = sign * self.g[j + a] * self.binomials[j + a][j];
+                t[j * p + a] = powers[j + a] * factor;
+            }
+        }
+        self.sandwich_add(&t, multipole, 1. / d.abs(), local);
+    }
+
+    /// Shifts the local expansion of a parent to its child and adds it there.
+    /// e is the childs center relative to the parents center.
+    pub fn l2l(&self, parent: &[Complex], e: Complex, child: &mut [Complex]) {
+        let p = self.order;
+        let powers = e.powers(p);
+
+        let mut t = vec![Complex::ZERO; p * p];
+        for j in 0..p {
+            for k in j..p {
+                t[j * p + k] = powers[k - j] * self.binomials[k][j];
+            }
+        }
+        self.sandwich_add(&t, parent, 1., child);
+    }
+
+    /// Evaluates the gradient ∇Φ of the local expansion at the relative position ζ.
+    /// The gradient is returned as the complex number ∂Φ/∂x + i * ∂Φ/∂y.
+    pub fn l2p(&self, local: &[Complex], zeta: Complex) -> Complex {
+        let p = self.order;
+        let powers = zeta.powers(p);
+
+        // ∇Φ = 2 * ∂Φ/∂conj(ζ), because Φ is real.
+        let mut gradient = Complex::ZERO;
+        for j in 0..p {
+            for k in 1..p {
+                gradient += local[j * p + k] * powers[j] * powers[k - 1].conj() * k as f64;
+            }
+        }
+        gradient * 2.
+    }
+
+    /// Calculates out += factor * T * X * T^H.
+    fn sandwich_add(&self, t: &[Complex], x: &[Complex], factor: f64, out: &mut [Complex]) {
+        let p = self.order;
+
+        let mut tx = vec![Complex::ZERO; p * p];
+        for i in 0..p {
+            for k in 0..p {
+                let t_ik = t[i * p + k];
+                if t_ik.re == 0. && t_ik.im == 0. { continue; }
+                for l in 0..p {
+                    tx[i * p + l] += t_ik * x[k * p + l];
+                }
+            }
+        }
+
+        for i in 0..p {
+            for j in 0..p {
+                let mut sum = Complex::ZERO;
+                for l in 0..p {
+                    sum += tx[i * p + l] * t[j * p + l].conj();
+                }
+                out[i * p + j] += sum * factor;
+            }
+        }
+    }
+}
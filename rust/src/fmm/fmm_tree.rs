@@ -0,0 +1,342 @@
+use crate::base::{Scalar, Object, Vec2, G, Softening, SofteningKernel};
+use crate::fmm::{Complex, Expansions, LEAF_CAPACITY, MAX_DEPTH};
+
+/// A cell of the adaptive quadtree.
+/// Positions and sizes are given in the normalized coordinates of the tree.
+#[derive(Debug, Clone)]
+struct Node {
+    center: Complex,
+    size: f64,
+    /// The range of the nodes bodies inside of FmmTree::bodies.
+    start: usize,
+    end: usize,
+    /// The children, indexed by their quadrant (see Cell::quadrant).
+    /// A child always has a larger index than its parent.
+    children: [Option<usize>; 4],
+}
+
+impl Node {
+    fn is_leaf(&self) -> bool {
+        self.children.iter().all(|child| child.is_none())
+    }
+
+    /// The radius of the circle around the cells center that contains the whole cell.
+    fn radius(&self) -> f64 {
+        self.size * std::f64::consts::FRAC_1_SQRT_2
+    }
+
+    fn children(&self) -> impl Iterator<Item = usize> + '_ {
+        self.children.iter().flatten().copied()
+    }
+}
+
+/// An adaptive quadtree for the Fast-Multipole-Method.
+/// Cells are split until they contain at most LEAF_CAPACITY bodies.
+///
+/// Every cell carries a multipole expansion of its bodies and a local expansion
+/// of the far field acting on it. The far field is gathered with a dual tree walk,
+/// that converts the multipole expansion of every well separated pair of cells
+/// into a local expansion (M2L), so the force calculation runs in O(n).
+///
+/// All coordinates are normalized to the root cell [0, 1]²,
+/// which keeps the powers in the expansions in a well behaved range.
+#[derive(Debug, Clone)]
+pub struct FmmTree {
+    expansions: Expansions,
+    theta: f64,
+    /// The size of the root cell, which converts the normalized coordinates back.
+    scale: f64,
+    /// The softening of the direct interactions, in normalized coordinates.
+    softening: Softening,
+    nodes: Vec<Node>,
+    /// The body indices sorted by the cells they are in.
+    bodies: Vec<usize>,
+    positions: Vec<Complex>,
+    masses: Vec<f64>,
+    multipoles: Vec<Complex>,
+    locals: Vec<Complex>,
+}
+
+impl FmmTree {
+    /// Creates the tree from a set of objects.
+    /// The order configures the number of terms kept per index of the expansions
+    /// and theta the separation criterion (radius_a + radius_b) / distance < θ.
+    pub fn create_from_objects(objects: &[Object], order: usize, theta: Scalar) -> Self {
+        let expansions = Expansions::new(order);
+        let (origin, scale) = Self::calc_tree_box(objects);
+
+        let positions = objects.iter()
+            .map(|object| Complex::new(
+                (object.x - origin.x) as f64 / scale,
+                (object.y - origin.y) as f64 / scale,
+            ))
+            .collect();
+        let masses = objects.iter().map(|object| object.m as f64).collect();
+
+        let mut fmm_tree = Self {
+            expansions,
+            theta: theta as f64,
+            scale,
+            softening: Softening::default(),
+            nodes: Vec::new(),
+            bodies: (0..objects.len()).collect(),
+            positions,
+            masses,
+            multipoles: Vec::new(),
+            locals: Vec::new(),
+        };
+        fmm_tree.build();
+        fmm_tree
+    }
+
+    /// Softens the direct interactions of neighbouring bodies.
+    /// The expansions of distant cells always use the unsoftened force,
+    /// which is exact for the spline kernel and a close approximation for the Plummer kernel.
+    pub fn with_softening(mut self, softening: Softening) -> Self {
+        self.softening = softening.scaled(self.scale as Scalar);
+        self
+    }
+
+    /// Returns the origin and the size of the smallest square containing all objects.
+    fn calc_tree_box(objects: &[Object]) -> (Vec2, f64) {
+        let Some(first) = objects.first() else { return (Vec2::new(0., 0.), 1.) };
+
+        let (mut min, mut max) = (first.pos(), first.pos());
+        for object in objects {
+            min.x = min.x.min(object.x); min.y = min.y.min(object.y);
+            max.x = max.x.max(object.x); max.y = max.y.max(object.y);
+        }
+        let size = (max.x - min.x).max(max.y - min.y) as f64;
+
+        // If all objects are at the same position any size works.
+        (min, if size > 0. { size } else { 1. })
+    }
+
+    /// Splits the cells until each leaf holds at most LEAF_CAPACITY bodies.
+    fn build(&mut self) {
+        self.nodes.push(Node {
+            center: Complex::new(0.5, 0.5),
+            size: 1.,
+            start: 0,
+            end: self.bodies.len(),
+            children: [None; 4],
+        });
+
+        let mut stack = vec![(0, 0)];
+        while let Some((index, depth)) = stack.pop() {
+            let Node { center, size, start, end, .. } = self.nodes[index];
+            if end - start <= LEAF_CAPACITY || depth >= MAX_DEPTH { continue; }
+
+            let mut quadrants: [Vec<usize>; 4] = Default::default();
+            for &body in &self.bodies[start..end] {
+                let pos = self.positions[body];
+                // See Cell::quadrant for the numbering scheme.
+                let quadrant = (pos.re >= center.re) as usize + (((pos.im >= center.im) as usize) << 1);
+                quadrants[quadrant].push(body);
+            }
+
+            let mut offset = start;
+            for (quadrant, bodies) in quadrants.iter().enumerate() {
+                if bodies.is_empty() { continue; }
+
+                self.bodies[offset..offset + bodies.len()].copy_from_slice(bodies);
+
+                let quarter = size / 4.;
+                let dx = if quadrant & 1 == 1 { quarter } else { -quarter };
+                let dy = if quadrant & 2 == 2 { quarter } else { -quarter };
+
+                let child = self.nodes.len();
+                self.nodes.push(Node {
+                    center: center + Complex::new(dx, dy),
+                    size: size / 2.,
+                    start: offset,
+                    end: offset + bodies.len(),
+                    children: [None; 4],
+                });
+                self.nodes[index].children[quadrant] = Some(child);
+                stack.push((child, depth + 1));
+
+                offset += bodies.len();
+            }
+        }
+    }
+
+    /// Calculates the total force that acts on every object,
+    /// in the same order as the objects the tree was created from.
+    pub fn calc_forces(&mut self) -> Vec<Vec2> {
+        self.upward_pass();
+        let mut gradients = self.interactions();
+        self.downward_pass(&mut gradients);
+
+        let factor = G as f64 / (self.scale * self.scale);
+        gradients.iter().zip(&self.masses)
+            .map(|(gradient, m)| {
+                let force = *gradient * (factor * m);
+                Vec2::new(force.re as Scalar, force.im as Scalar)
+            })
+            .collect()
+    }
+
+    /// Builds the multipole expansions from the leaves up to the root (P2M and M2M).
+    fn upward_pass(&mut self) {
+        let len = self.expansions.size();
+        self.multipoles = vec![Complex::ZERO; self.nodes.len() * len];
+
+        // Children always come after their parents.
+        for index in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[index];
+            let (head, tail) = self.multipoles.split_at_mut((index + 1) * len);
+            let multipole = &mut head[index * len..];
+
+            if node.is_leaf() {
+                for &body in &self.bodies[node.start..node.end] {
+                    let s = self.positions[body] - node.center;
+                    self.expansions.p2m(s, self.masses[body], multipole);
+                }
+                continue;
+            }
+
+            for child in node.children() {
+                let offset = (child - index - 1) * len;
+                let d = self.nodes[child].center - node.center;
+                self.expansions.m2m(&tail[offset..offset + len], d, multipole);
+            }
+        }
+    }
+
+    /// Walks over all pairs of cells.
+    /// Well separated pairs are converted into local expansions (M2L),
+    /// neighbouring leaves interact directly (P2P).
+    /// Returns the gradients of the near field for every body.
+    fn interactions(&mut self) -> Vec<Complex> {
+        let len = self.expansions.size();
+        self.locals = vec![Complex::ZERO; self.nodes.len() * len];
+        let mut gradients = vec![Complex::ZERO; self.bodies.len()];
+
+        if self.bodies.is_empty() { return gradients }
+
+        // (target, source)
+        let mut stack = vec![(0, 0)];
+        while let Some((target, source)) = stack.pop() {
+            let (t_node, s_node) = (&self.nodes[target], &self.nodes[source]);
+
+            if target == source {
+                if t_node.is_leaf() {
+                    self.p2p(target, source, &mut gradients);
+                }
+                for t_child in t_node.children() {
+                    for s_child in t_node.children() {
+                        stack.push((t_child, s_child));
+                    }
+                }
+                continue;
+            }
+
+            let d = t_node.center - s_node.center;
+            if t_node.radius() + s_node.radius() < self.theta * d.abs() {
+                let multipole = &self.multipoles[source * len..(source + 1) * len];
+                let local = &mut self.locals[target * len..(target + 1) * len];
+                self.expansions.m2l(multipole, d, local);
+                continue;
+            }
+
+            match (t_node.is_leaf(), s_node.is_leaf()) {
+                (true, true) => self.p2p(target, source, &mut gradients),
+                (false, true) => stack.extend(t_node.children().map(|child| (child, source))),
+                (true, false) => stack.extend(s_node.children().map(|child| (target, child))),
+                (false, false) => {
+                    // The larger cell is split.
+                    if t_node.size >= s_node.size {
+                        stack.extend(t_node.children().map(|child| (child, source)));
+                    } else {
+                        stack.extend(s_node.children().map(|child| (target, child)));
+                    }
+                }
+            }
+        }
+        gradients
+    }
+
+    /// Adds the direct interaction of the bodies in the source cell with the bodies in the target cell.
+    fn p2p(&self, target: usize, source: usize, gradients: &mut [Complex]) {
+        let (t_node, s_node) = (&self.nodes[target], &self.nodes[source]);
+
+        for &body in &self.bodies[t_node.start..t_node.end] {
+            let pos = self.positions[body];
+            for &body2 in &self.bodies[s_node.start..s_node.end] {
+                if body == body2 { continue; }
+
+                let r_vec = self.positions[body2] - pos;
+                let r_sqr = r_vec.norm_sqr();
+                if r_sqr == 0. { continue; }
+
+                let factor = match self.softening.kernel {
+                    SofteningKernel::None => 1. / (r_sqr * r_sqr.sqrt()),
+                    _ => self.softening.factor(r_sqr.sqrt() as Scalar) as f64,
+                };
+                gradients[body] += r_vec * (self.masses[body2] * factor);
+            }
+        }
+    }
+
+    /// Shifts the local expansions from the root down to the leaves (L2L)
+    /// and evaluates them for every body (L2P).
+    fn downward_pass(&mut self, gradients: &mut [Complex]) {
+        let len = self.expansions.size();
+
+        for index in 0..self.nodes.len() {
+            let node = &self.nodes[index];
+            let (head, tail) = self.locals.split_at_mut((index + 1) * len);
+            let local = &head[index * len..];
+
+            if node.is_leaf() {
+                for &body in &self.bodies[node.start..node.end] {
+                    let zeta = self.positions[body] - node.center;
+                    gradients[body] += self.expansions.l2p(local, zeta);
+                }
+                continue;
+            }
+
+            for child in node.children() {
+                let offset = (child - index - 1) * len;
+                let e = self.nodes[child].center - node.center;
+                self.expansions.l2l(local, e, &mut tail[offset..offset + len]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod fmm_tree_tests {
+    use crate::base::*;
+    use crate::fmm::{FmmTree, ORDER, THETA};
+    use crate::direct::get_forces;
+    use crate::tests::objects;
+
+    #[test]
+    fn test_forces_match_direct_summation() {
+        let objects = objects(1000, 10007., 9973., |i| 1e16 * (1. + i % 13.));
+        let exact = get_forces(&objects, Softening::default());
+
+        let forces = FmmTree::create_from_objects(&objects, ORDER, THETA).calc_forces();
+
+        for (force, exact_force) in forces.iter().zip(&exact) {
+            let error = (*force - *exact_force).length() / exact_force.length();
+            assert!(error < 1e-3, "relative error {error} is too large");
+        }
+    }
+
+    #[test]
+    fn test_higher_order_is_more_accurate() {
+        let objects = objects(500, 10007., 9973., |i| 1e16 * (1. + i % 13.));
+        let exact = get_forces(&objects, Softening::default());
+
+        let max_error = |order: usize| {
+            let forces = FmmTree::create_from_objects(&objects, order, THETA).calc_forces();
+            forces.iter().zip(&exact)
+                .map(|(force, exact_force)| (*force - *exact_force).length() / exact_force.length())
+                .fold(0., Scalar::max)
+        };
+        assert!(max_error(8) < max_error(2));
+    }
+}
@@ -1,4 +1,5 @@
-use crate::base::{Scalar, Vec2, Object};
+use crate::base::{Scalar, Vec2, Vec3, Object, Object3D};
+use crate::simulation::Simulation;
 use godot::{builtin, engine, obj};
 
 impl From<builtin::Vector2> for Vec2 {
@@ -19,6 +20,26 @@ impl From<Vec2> for builtin::Vector2 {
     }
 }
 
+impl From<builtin::Vector3> for Vec3 {
+    fn from(vector3: builtin::Vector3) -> Self {
+        Self {
+            x: vector3.x as Scalar,
+            y: vector3.y as Scalar,
+            z: vector3.z as Scalar,
+        }
+    }
+}
+
+impl From<Vec3> for builtin::Vector3 {
+    fn from(vec3: Vec3) -> Self {
+        builtin::Vector3 {
+            x: vec3.x as f32,
+            y: vec3.y as f32,
+            z: vec3.z as f32,
+        }
+    }
+}
+
 impl From<obj::Gd<engine::RigidBody2D>> for Object {
     fn from(rigid_body2d: obj::Gd<engine::RigidBody2D>) -> Self {
         let pos: Vec2 = rigid_body2d.get_position().into();
@@ -32,3 +53,32 @@ impl Object {
         Self::new(pos.x, pos.y, rigid_body2d.get_mass() as Scalar)
     }
 }
+
+impl Object3D {
+    pub fn copy_from_rigidbody3d(rigid_body3d: &obj::Gd<engine::RigidBody3D>) -> Self {
+        let pos: Vec3 = rigid_body3d.get_position().into();
+        Self::new(pos.x, pos.y, pos.z, rigid_body3d.get_mass() as Scalar)
+    }
+}
+
+impl Simulation {
+    /// Adds the position, velocity, mass and radius of the rigid body to the simulation
+    /// and returns the index of the new body.
+    pub fn add_rigidbody(&mut self, rigid_body2d: &obj::Gd<engine::RigidBody2D>) -> usize {
+        self.add_body_with_radius(
+            rigid_body2d.get_position().into(),
+            rigid_body2d.get_linear_velocity().into(),
+            rigid_body2d.get_mass() as Scalar,
+            radius(rigid_body2d),
+        )
+    }
+}
+
+/// Returns the radius of the circle shape of the rigid body,
+/// which is 0 if its CollisionShape2D is missing or not a circle.
+pub fn radius(rigid_body2d: &obj::Gd<engine::RigidBody2D>) -> Scalar {
+    rigid_body2d.try_get_node_as::<engine::CollisionShape2D>("CollisionShape2D")
+        .and_then(|collision_shape| collision_shape.get_shape())
+        .and_then(|shape| shape.try_cast::<engine::CircleShape2D>())
+        .map_or(0., |circle| circle.get_radius() as Scalar)
+}
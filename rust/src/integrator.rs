@@ -0,0 +1,29 @@
+/// The method the simulation uses to advance positions and velocities.
+/// With the godot feature it can be exported as a property.
+#[cfg_attr(feature = "godot", derive(godot::prelude::Property, godot::prelude::Export))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(i64)]
+pub enum Integrator {
+    /// First order, the same method Godots physics engine uses.
+    SemiImplicitEuler = 0,
+    /// Kick-drift-kick leapfrog, second order and symplectic.
+    #[default]
+    Leapfrog = 1,
+    /// Velocity Verlet, second order and symplectic.
+    /// It produces the same trajectory as the leapfrog,
+    /// but updates the positions with the accelerations of the previous step.
+    VelocityVerlet = 2,
+    /// The classic fourth order Runge-Kutta method.
+    /// It is not symplectic and needs four force calculations per step.
+    RungeKutta4 = 3,
+    /// Yoshidas fourth order symplectic method,
+    /// which chains three leapfrog steps with the weights YOSHIDA_W1, YOSHIDA_W0 and YOSHIDA_W1.
+    Yoshida4 = 4,
+    /// The fourth order Hermite predictor-corrector method.
+    /// It needs the jerk, so it always uses the direct approach to calculate the forces.
+    Hermite4 = 5,
+}
+
+/// The weights of the Yoshida method: w1 = 1 / (2 - ∛2) and w0 = -∛2 / (2 - ∛2).
+pub const YOSHIDA_W1: f64 = 1.351_207_191_959_657_8;
+pub const YOSHIDA_W0: f64 = -1.702_414_383_919_315_3;
@@ -0,0 +1,136 @@
+//! Force kernels, which sum up the forces of many particles onto one object.
+//! The particles are processed in blocks, which the compiler turns into SIMD instructions.
+
+use crate::base::{Scalar, Object, Vec2, Softening, SofteningKernel, G};
+use crate::base::Particles;
+use std::ops::Range;
+
+/// The number of particles of a block.
+/// The forces of a block are calculated in one loop without dependencies between the iterations,
+/// which the compiler can vectorize.
+pub const BLOCK: usize = 64;
+
+/// The number of partial sums the forces of a block are added to.
+/// The floating point additions can not be reordered by the compiler,
+/// so the partial sums are needed to add several forces at once.
+pub const LANES: usize = 8;
+
+/// Returns the total force the particles exert onto the object.
+/// Particles at the position of the object do not exert a force, like in Softening::force.
+/// The spline kernel can not be vectorized and is summed up one particle at a time, like in force_scalar.
+pub fn force(obj: Object, particles: &Particles, softening: Softening) -> Vec2 {
+    force_of_range(obj, particles, 0..particles.len(), softening)
+}
+
+/// Returns the total force of all particles except the one with the index,
+/// which is the object itself, see force.
+pub fn force_without(obj: Object, index: usize, particles: &Particles, softening: Softening) -> Vec2 {
+    force_of_range(obj, particles, 0..index, softening) + force_of_range(obj, particles, index + 1..particles.len(), softening)
+}
+
+/// Returns the total force the particles in the range exert onto the object, see force.
+fn force_of_range(obj: Object, particles: &Particles, range: Range<usize>, softening: Softening) -> Vec2 {
+    let length_sqr = match softening.kernel {
+        SofteningKernel::None => 0.,
+        SofteningKernel::Plummer => softening.length * softening.length,
+        SofteningKernel::Spline => {
+            return range.map(|i| softening.force(obj, particles.get(i))).fold(Vec2::new(0., 0.), |total, force| total + force)
+        }
+    };
+    let (x, y, m) = (&particles.x[range.clone()], &particles.y[range.clone()], &particles.m[range]);
+
+    let mut force_x = [0.; LANES];
+    let mut force_y = [0.; LANES];
+
+    let blocks = x.chunks_exact(BLOCK)
+        .zip(y.chunks_exact(BLOCK))
+        .zip(m.chunks_exact(BLOCK));
+
+    for ((x, y), m) in blocks {
+        // Fixed size arrays let the compiler remove the bounds checks.
+        let x: &[Scalar; BLOCK] = x.try_into().unwrap();
+        let y: &[Scalar; BLOCK] = y.try_into().unwrap();
+        let m: &[Scalar; BLOCK] = m.try_into().unwrap();
+
+        let mut block_force_x = [0.; BLOCK];
+        let mut block_force_y = [0.; BLOCK];
+        for i in 0..BLOCK {
+            let (dx, dy) = (x[i] - obj.x, y[i] - obj.y);
+            let m_factor = m_factor(dx, dy, m[i], length_sqr);
+
+            block_force_x[i] = m_factor * dx;
+            block_force_y[i] = m_factor * dy;
+        }
+
+        let partial_forces = block_force_x.chunks_exact(LANES).zip(block_force_y.chunks_exact(LANES));
+        for (partial_force_x, partial_force_y) in partial_forces {
+            for lane in 0..LANES {
+                force_x[lane] += partial_force_x[lane];
+                force_y[lane] += partial_force_y[lane];
+            }
+        }
+    }
+
+    let remainder = m.len() - m.len() % BLOCK;
+    for i in remainder..m.len() {
+        let (dx, dy) = (x[i] - obj.x, y[i] - obj.y);
+        let m_factor = m_factor(dx, dy, m[i], length_sqr);
+
+        force_x[0] += m_factor * dx;
+        force_y[0] += m_factor * dy;
+    }
+
+    let total_x: Scalar = force_x.iter().sum();
+    let total_y: Scalar = force_y.iter().sum();
+    G * obj.m * Vec2::new(total_x, total_y)
+}
+
+/// Returns m * K(r) of the None and Plummer kernels without branches (see Softening::factor).
+#[inline(always)]
+fn m_factor(dx: Scalar, dy: Scalar, m: Scalar, length_sqr: Scalar) -> Scalar {
+    let r_sqr = dx * dx + dy * dy;
+    let softened_r_sqr = r_sqr + length_sqr;
+    let m_factor = m / (softened_r_sqr * softened_r_sqr.sqrt());
+
+    // Is a select instead of a branch, the division by zero is discarded.
+    if r_sqr == 0. { 0. } else { m_factor }
+}
+
+/// The scalar fallback of force, which works with every softening kernel.
+pub fn force_scalar(obj: Object, particles: &Particles, softening: Softening) -> Vec2 {
+    let mut total_force = Vec2::new(0., 0.);
+
+    for i in 0..particles.len() {
+        total_force += softening.force(obj, particles.get(i));
+    }
+    total_force
+}
+
+#[cfg(test)]
+mod kernel_tests {
+    use crate::base::*;
+    use crate::base::Particles;
+    use crate::kernel::*;
+    use crate::tests::objects;
+
+    #[test]
+    fn test_vectorized_force_matches_scalar_force() {
+        // Not a multiple of BLOCK, and one particle is at the position of the object.
+        let objects = objects(3 * BLOCK + 5, 101., 97., |i| 1e10 + i);
+        let particles = Particles::from_objects(&objects);
+
+        for kernel in [SofteningKernel::None, SofteningKernel::Plummer, SofteningKernel::Spline] {
+            let softening = Softening::new(kernel, 3.);
+
+            for (i, obj) in objects.iter().enumerate() {
+                let expected = force_scalar(*obj, &particles, softening);
+                let force = force(*obj, &particles, softening);
+                assert!((force - expected).length() <= 1e-4 * expected.length());
+
+                // Skipping the object itself by its index does not change the force.
+                let force_without = force_without(*obj, i, &particles, softening);
+                assert!((force_without - expected).length() <= 1e-4 * expected.length());
+            }
+        }
+    }
+}
@@ -1,21 +1,60 @@
+//! The simulation core does not depend on Godot.
+//! The Godot adapter (the Space node) is only compiled with the godot feature,
+//! which is enabled by default.
+
+// Conversions from and to Scalar are only needed when it is f32.
+#![cfg_attr(feature = "f64", allow(clippy::unnecessary_cast))]
+
+#[cfg(feature = "godot")]
 use godot::prelude::*;
 
+#[cfg(feature = "godot")]
 struct GodotExtension;
 
+#[cfg(feature = "godot")]
 #[gdextension]
 unsafe impl ExtensionLibrary for GodotExtension {}
 
+#[cfg(feature = "godot")]
 pub mod godot_aliases;
+#[cfg(feature = "godot")]
 pub mod space;
+#[cfg(feature = "godot")]
+pub mod space_3d;
 
 pub mod base;
+pub mod solver;
+pub mod simulation;
+pub mod integrator;
+pub mod timestep;
+pub mod collision;
+pub mod diagnostics;
+pub mod kernel;
 
 pub mod direct;
 pub mod barnes_hut;
+pub mod fmm;
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use crate::base::{Object, Object3D, Scalar};
+
+    /// Returns n objects that are spread deterministically over a width × height area,
+    /// the object i has the mass mass(i).
+    pub fn objects(n: usize, width: Scalar, height: Scalar, mass: impl Fn(Scalar) -> Scalar) -> Vec<Object> {
+        (0..n)
+            .map(|i| i as Scalar)
+            .map(|i| Object::new((i * 37.) % width, (i * 53.) % height, mass(i)))
+            .collect()
+    }
+
+    /// The 3D counterpart of objects, spread over a 101 × 97 × 89 box.
+    pub fn objects_3d(n: usize, mass: impl Fn(Scalar) -> Scalar) -> Vec<Object3D> {
+        (0..n)
+            .map(|i| i as Scalar)
+            .map(|i| Object3D::new((i * 37.) % 101., (i * 53.) % 97., (i * 71.) % 89., mass(i)))
+            .collect()
+    }
 
     #[test]
     fn test() {
@@ -0,0 +1,999 @@
+use crate::base::{Scalar, Object, Vec2, Softening};
+use crate::solver::{GravitySolver, Algorithm};
+use crate::integrator::{Integrator, YOSHIDA_W0, YOSHIDA_W1};
+use crate::timestep::{Timestep, Timestepping};
+use crate::collision::{self, Collisions, Merger};
+use crate::diagnostics::{self, Diagnostics, Drift};
+use crate::direct::{self, Direct};
+use crate::barnes_hut::{BarnesHut, Quadtree};
+use crate::fmm::Fmm;
+
+/// A gravity simulation that does not depend on Godot.
+/// It owns the positions, velocities and masses of all bodies and steps itself,
+/// so it can be run, tested and benchmarked headlessly.
+#[derive(Debug, Clone, Default)]
+pub struct Simulation {
+    pub positions: Vec<Vec2>,
+    pub velocities: Vec<Vec2>,
+    pub masses: Vec<Scalar>,
+    /// The radii of the circles that are used to detect collisions.
+    pub radii: Vec<Scalar>,
+    /// The angular momentum of every body around its own center.
+    /// It is only changed by mergers and does not influence the movement.
+    pub spins: Vec<Scalar>,
+
+    /// The elapsed simulation time.
+    pub time: Scalar,
+
+    pub integrator: Integrator,
+    /// The accelerations at the current positions, which are reused by the next step.
+    /// Call reset_accelerations after changing the bodies directly.
+    accelerations: Option<Vec<Vec2>>,
+    /// The jerks at the current positions, only used by the Hermite method.
+    jerks: Option<Vec<Vec2>>,
+
+    pub timestep: Timestep,
+    /// The timestep level of every body, see Timestep::required_level.
+    levels: Vec<u32>,
+
+    /// Applied by all solvers.
+    pub softening: Softening,
+
+    /// How overlapping bodies are handled, see resolve_collisions.
+    pub collisions: Collisions,
+
+    /// Calculates the diagnostics after every advance, see update_diagnostics.
+    pub diagnose: bool,
+    /// The diagnostics of the last update.
+    diagnostics: Option<Diagnostics>,
+    /// The diagnostics the drift is measured against.
+    /// They are taken again after bodies were added or removed.
+    reference_diagnostics: Option<Diagnostics>,
+
+    /// Selects which of the solvers is used.
+    pub algorithm: Algorithm,
+    pub direct: Direct,
+    pub barnes_hut: BarnesHut,
+    pub fmm: Fmm,
+}
+
+impl Simulation {
+    pub fn new(algorithm: Algorithm) -> Self {
+        Self { algorithm, ..Default::default() }
+    }
+
+    /// Adds a body without a radius and returns its index.
+    pub fn add_body(&mut self, pos: Vec2, vel: Vec2, m: Scalar) -> usize {
+        self.add_body_with_radius(pos, vel, m, 0.)
+    }
+
+    /// Adds a body with the radius, which is used to detect collisions, and returns its index.
+    pub fn add_body_with_radius(&mut self, pos: Vec2, vel: Vec2, m: Scalar, radius: Scalar) -> usize {
+        self.positions.push(pos);
+        self.velocities.push(vel);
+        self.masses.push(m);
+        self.radii.push(radius);
+        self.spins.push(0.);
+        self.reset_accelerations();
+        self.barnes_hut.reset();
+        self.levels.clear();
+        self.reset_diagnostics();
+        self.positions.len() - 1
+    }
+
+    /// Removes the body, the bodies after it move down by one index.
+    pub fn remove_body(&mut self, i: usize) {
+        self.positions.remove(i);
+        self.velocities.remove(i);
+        self.masses.remove(i);
+        self.radii.remove(i);
+        self.spins.remove(i);
+        self.reset_accelerations();
+        self.barnes_hut.reset();
+        self.levels.clear();
+        self.reset_diagnostics();
+    }
+
+    /// Removes all bodies.
+    pub fn clear(&mut self) {
+        self.positions.clear();
+        self.velocities.clear();
+        self.masses.clear();
+        self.radii.clear();
+        self.spins.clear();
+        self.reset_accelerations();
+        self.barnes_hut.reset();
+        self.levels.clear();
+        self.reset_diagnostics();
+    }
+
+    /// Forces the next step to recalculate the accelerations.
+    /// Needs to be called after the positions or masses were changed from outside of the simulation.
+    /// The timestep levels are kept, the integrators call this in the middle of a frame.
+    pub fn reset_accelerations(&mut self) {
+        self.accelerations = None;
+        self.jerks = None;
+    }
+
+    /// Forgets the diagnostics, so the drift is measured from the next update on.
+    /// Needs to be called after the bodies were changed from outside of the simulation.
+    pub fn reset_diagnostics(&mut self) {
+        self.diagnostics = None;
+        self.reference_diagnostics = None;
+    }
+
+    /// Calculates the conserved quantities of the bodies.
+    /// The potential energy uses the softening of the simulation
+    /// and is calculated with the quadtree above DIRECT_POTENTIAL_CUTOFF bodies.
+    pub fn calc_diagnostics(&mut self) -> Diagnostics {
+        let objects = self.objects();
+        let softening = self.softening;
+
+        let potential_energy = if objects.len() > diagnostics::DIRECT_POTENTIAL_CUTOFF {
+            let qtree = self.barnes_hut.quadtree(&objects);
+            // Every pair is counted twice.
+            objects.iter().enumerate().map(|(i, object)| qtree.calc_potential(*object, i, diagnostics::POTENTIAL_THETA, softening)).sum::<Scalar>() / 2.
+        } else {
+            direct::get_potential_energy(&objects, softening)
+        };
+        Diagnostics::new(&self.positions, &self.velocities, &self.masses, &self.spins, potential_energy)
+    }
+
+    /// Calculates the diagnostics and keeps them,
+    /// the first ones after a reset become the reference of the drift.
+    pub fn update_diagnostics(&mut self) -> Diagnostics {
+        let diagnostics = self.calc_diagnostics();
+        self.reference_diagnostics.get_or_insert(diagnostics);
+        self.diagnostics = Some(diagnostics);
+        diagnostics
+    }
+
+    /// Returns the diagnostics of the last update.
+    pub fn diagnostics(&self) -> Option<Diagnostics> {
+        self.diagnostics
+    }
+
+    /// Returns the drift of the last diagnostics from the reference.
+    pub fn diagnostics_drift(&self) -> Option<Drift> {
+        Some(self.diagnostics?.drift(&self.reference_diagnostics?))
+    }
+
+    /// Returns the timestep level of every body.
+    /// A body on level k was last advanced with the timestep delta / 2^k.
+    pub fn levels(&self) -> &[u32] {
+        &self.levels
+    }
+
+    /// Returns the number of bodies.
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Returns the bodies as objects, which are used by the solvers.
+    pub fn objects(&self) -> Vec<Object> {
+        self.positions.iter().zip(&self.masses)
+            .map(|(pos, m)| Object::new(pos.x, pos.y, *m))
+            .collect()
+    }
+
+    /// Returns the solver of the selected algorithm.
+    pub fn solver(&mut self) -> &mut dyn GravitySolver {
+        match self.algorithm {
+            Algorithm::Direct | Algorithm::DirectSimd => {
+                self.direct.simd = self.algorithm == Algorithm::DirectSimd;
+                self.direct.softening = self.softening;
+                &mut self.direct
+            }
+            Algorithm::BarnesHut | Algorithm::BarnesHutParallel => {
+                self.barnes_hut.parallel = self.algorithm == Algorithm::BarnesHutParallel;
+                self.barnes_hut.softening = self.softening;
+                &mut self.barnes_hut
+            }
+            Algorithm::Fmm => {
+                self.fmm.softening = self.softening;
+                &mut self.fmm
+            }
+        }
+    }
+
+    /// Calculates the total force that acts on every body.
+    pub fn calc_forces(&mut self) -> Vec<Vec2> {
+        let objects = self.objects();
+        self.solver().calc_forces(&objects)
+    }
+
+    /// Calculates the acceleration of every body.
+    /// Bodies without a mass are not accelerated.
+    pub fn calc_accelerations(&mut self) -> Vec<Vec2> {
+        let forces = self.calc_forces();
+        self.to_accelerations(forces)
+    }
+
+    /// Calculates the accelerations the bodies would have at the provided positions.
+    fn calc_accelerations_at(&mut self, positions: &[Vec2]) -> Vec<Vec2> {
+        let objects: Vec<Object> = positions.iter().zip(&self.masses)
+            .map(|(pos, m)| Object::new(pos.x, pos.y, *m))
+            .collect();
+
+        let forces = self.solver().calc_forces(&objects);
+        self.to_accelerations(forces)
+    }
+
+    /// Calculates the accelerations and the jerks with the direct approach
+    /// for the bodies at the provided positions and velocities.
+    fn calc_accelerations_and_jerks_at(&self, positions: &[Vec2], velocities: &[Vec2]) -> (Vec<Vec2>, Vec<Vec2>) {
+        let objects: Vec<Object> = positions.iter().zip(&self.masses)
+            .map(|(pos, m)| Object::new(pos.x, pos.y, *m))
+            .collect();
+
+        let (forces, jerks) = direct::get_forces_and_jerks(&objects, velocities, self.softening);
+        (self.to_accelerations(forces), self.to_accelerations(jerks))
+    }
+
+    /// Divides the forces (or their derivatives) by the masses.
+    fn to_accelerations(&self, forces: Vec<Vec2>) -> Vec<Vec2> {
+        forces.into_iter().zip(&self.masses)
+            .map(|(force, m)| if *m == 0. { Vec2::new(0., 0.) } else { force / *m })
+            .collect()
+    }
+
+    /// Advances the simulation by dt with the selected integrator.
+    pub fn step(&mut self, dt: Scalar) {
+        match self.integrator {
+            Integrator::SemiImplicitEuler => self.semi_implicit_euler_step(dt),
+            Integrator::Leapfrog => self.leapfrog_step(dt),
+            Integrator::VelocityVerlet => self.velocity_verlet_step(dt),
+            Integrator::RungeKutta4 => self.runge_kutta4_step(dt),
+            Integrator::Yoshida4 => self.yoshida4_step(dt),
+            Integrator::Hermite4 => self.hermite4_step(dt),
+        }
+        self.time += dt;
+    }
+
+    /// Advances the simulation by a whole frame,
+    /// which is split into timesteps as configured by the timestep field.
+    pub fn advance(&mut self, delta: Scalar) {
+        match self.timestep.mode {
+            Timestepping::Fixed => self.step(delta),
+            Timestepping::Adaptive => self.adaptive_advance(delta),
+            Timestepping::Block => self.block_advance(delta),
+        }
+        if self.diagnose { self.update_diagnostics(); }
+    }
+
+    /// Returns the number of the smallest substeps (ticks) a frame is divided into.
+    fn ticks_per_frame(&self) -> u64 {
+        1 << self.timestep.max_level
+    }
+
+    /// Returns the number of ticks of a step on the level.
+    fn ticks_of_level(&self, level: u32) -> u64 {
+        1 << (self.timestep.max_level - level)
+    }
+
+    /// Returns the level for a new step starting at the tick,
+    /// which satisfies the required level and ends on a multiple of its own length,
+    /// so steps of different levels always line up.
+    fn aligned_level(&self, required_level: u32, tick: u64) -> u32 {
+        let mut level = required_level.min(self.timestep.max_level);
+        while !tick.is_multiple_of(self.ticks_of_level(level)) {
+            level += 1;
+        }
+        level
+    }
+
+    /// Starts the bodies on the levels their current accelerations require,
+    /// if they do not have levels yet, which is only the case after bodies were added or removed.
+    /// Like in the steps, the derivatives of the accelerations are estimated from two accelerations
+    /// of the selected solver, the second one after a drift over the smallest timestep.
+    /// The levels are also clamped, in case the maximum level was lowered.
+    fn init_levels(&mut self, delta: Scalar) {
+        if self.levels.len() != self.len() {
+            let dt = delta / self.ticks_per_frame() as Scalar;
+            let accelerations = self.current_accelerations();
+            let probe_positions: Vec<Vec2> = self.positions.iter().zip(&self.velocities)
+                .map(|(pos, vel)| *pos + *vel * dt)
+                .collect();
+            let probe_accelerations = self.calc_accelerations_at(&probe_positions);
+
+            self.levels = accelerations.iter().zip(&probe_accelerations)
+                .map(|(acc, probe_acc)| self.timestep.required_level(*acc, (*probe_acc - *acc) / dt, delta))
+                .collect();
+            self.accelerations = Some(accelerations);
+        }
+        let max_level = self.timestep.max_level;
+        for level in &mut self.levels {
+            *level = (*level).min(max_level);
+        }
+    }
+
+    /// Advances all bodies with the same timestep, which is adapted after every substep.
+    fn adaptive_advance(&mut self, delta: Scalar) {
+        self.init_levels(delta);
+
+        let mut tick = 0;
+        while tick < self.ticks_per_frame() {
+            let required_level = self.levels.iter().copied().max().unwrap_or(0);
+            let level = self.aligned_level(required_level, tick);
+            let ticks = self.ticks_of_level(level);
+            let dt = delta * ticks as Scalar / self.ticks_per_frame() as Scalar;
+
+            let old_accelerations = self.current_accelerations();
+            self.accelerations = Some(old_accelerations.clone());
+
+            self.step(dt);
+
+            let new_accelerations = self.current_accelerations();
+            for (i, (old_acc, new_acc)) in old_accelerations.iter().zip(&new_accelerations).enumerate() {
+                self.levels[i] = self.timestep.required_level(*new_acc, (*new_acc - *old_acc) / dt, delta);
+            }
+            self.accelerations = Some(new_accelerations);
+
+            tick += ticks;
+        }
+    }
+
+    /// Advances every body with its own power-of-two timestep
+    /// with a kick-drift-kick leapfrog (block timesteps).
+    /// All bodies are drifted to the next tick at which one of them finishes its step,
+    /// but only the forces on the bodies that finish their step are calculated.
+    fn block_advance(&mut self, delta: Scalar) {
+        self.init_levels(delta);
+
+        let mut accelerations = self.current_accelerations();
+        let h = delta / self.ticks_per_frame() as Scalar;
+
+        let mut tick = 0;
+        while tick < self.ticks_per_frame() {
+            // The first half kick for the bodies that begin a step.
+            for ((vel, acc), level) in self.velocities.iter_mut().zip(&accelerations).zip(&self.levels) {
+                let ticks = 1 << (self.timestep.max_level - level);
+                if tick.is_multiple_of(ticks) {
+                    *vel += *acc * (h * ticks as Scalar / 2.);
+                }
+            }
+
+            // The steps are aligned to their length, so every body finishes its step at the next multiple of it.
+            let end_tick = self.levels.iter()
+                .map(|&level| (tick / self.ticks_of_level(level) + 1) * self.ticks_of_level(level))
+                .min()
+                .unwrap_or(self.ticks_per_frame());
+
+            self.drift(h * (end_tick - tick) as Scalar);
+            tick = end_tick;
+
+            let active: Vec<usize> = (0..self.len())
+                .filter(|&i| end_tick.is_multiple_of(self.ticks_of_level(self.levels[i])))
+                .collect();
+            if active.is_empty() { continue; }
+
+            let objects = self.objects();
+            let forces = self.solver().calc_forces_on(&objects, &active);
+
+            // The second half kick and the new level for the bodies that finish their step.
+            for (i, force) in active.into_iter().zip(forces) {
+                let m = self.masses[i];
+                let acc = if m == 0. { Vec2::new(0., 0.) } else { force / m };
+                let dt = h * self.ticks_of_level(self.levels[i]) as Scalar;
+
+                self.velocities[i] += acc * (dt / 2.);
+
+                let required_level = self.timestep.required_level(acc, (acc - accelerations[i]) / dt, delta);
+                self.levels[i] = self.aligned_level(required_level, end_tick);
+                accelerations[i] = acc;
+            }
+        }
+        self.accelerations = Some(accelerations);
+        self.time += delta;
+    }
+
+    /// Returns the accelerations at the current positions,
+    /// reusing the ones from the previous step if possible.
+    fn current_accelerations(&mut self) -> Vec<Vec2> {
+        match self.accelerations.take() {
+            Some(accelerations) if accelerations.len() == self.len() => accelerations,
+            _ => self.calc_accelerations(),
+        }
+    }
+
+    fn semi_implicit_euler_step(&mut self, dt: Scalar) {
+        let accelerations = self.calc_accelerations();
+
+        self.kick(&accelerations, dt);
+        self.drift(dt);
+    }
+
+    /// Kick-drift-kick: needs only one force calculation per step,
+    /// because the accelerations of the last kick are reused by the first kick of the next step.
+    fn leapfrog_step(&mut self, dt: Scalar) {
+        let accelerations = self.current_accelerations();
+        self.kick(&accelerations, dt / 2.);
+
+        self.drift(dt);
+
+        let accelerations = self.calc_accelerations();
+        self.kick(&accelerations, dt / 2.);
+        self.accelerations = Some(accelerations);
+    }
+
+    fn velocity_verlet_step(&mut self, dt: Scalar) {
+        let accelerations = self.current_accelerations();
+
+        for ((pos, vel), acc) in self.positions.iter_mut().zip(&self.velocities).zip(&accelerations) {
+            *pos += *vel * dt + *acc * (dt * dt / 2.);
+        }
+
+        let new_accelerations = self.calc_accelerations();
+        for ((vel, acc), new_acc) in self.velocities.iter_mut().zip(&accelerations).zip(&new_accelerations) {
+            *vel += (*acc + *new_acc) * (dt / 2.);
+        }
+        self.accelerations = Some(new_accelerations);
+    }
+
+    fn runge_kutta4_step(&mut self, dt: Scalar) {
+        let (x0, v0) = (self.positions.clone(), self.velocities.clone());
+
+        // Returns x0 + dx * h for every body.
+        let offset = |x0: &[Vec2], dx: &[Vec2], h: Scalar| -> Vec<Vec2> {
+            x0.iter().zip(dx).map(|(x, dx)| *x + *dx * h).collect()
+        };
+
+        let a1 = self.calc_accelerations_at(&x0);
+        let v1 = v0.clone();
+
+        let x2 = offset(&x0, &v1, dt / 2.);
+        let v2 = offset(&v0, &a1, dt / 2.);
+        let a2 = self.calc_accelerations_at(&x2);
+
+        let x3 = offset(&x0, &v2, dt / 2.);
+        let v3 = offset(&v0, &a2, dt / 2.);
+        let a3 = self.calc_accelerations_at(&x3);
+
+        let x4 = offset(&x0, &v3, dt);
+        let v4 = offset(&v0, &a3, dt);
+        let a4 = self.calc_accelerations_at(&x4);
+
+        for i in 0..self.len() {
+            self.positions[i] += (v1[i] + 2. * v2[i] + 2. * v3[i] + v4[i]) * (dt / 6.);
+            self.velocities[i] += (a1[i] + 2. * a2[i] + 2. * a3[i] + a4[i]) * (dt / 6.);
+        }
+        self.reset_accelerations();
+    }
+
+    /// Drift-kick-drift form with the coefficients c1 = c4 = w1 / 2, c2 = c3 = (w0 + w1) / 2,
+    /// d1 = d3 = w1 and d2 = w0.
+    fn yoshida4_step(&mut self, dt: Scalar) {
+        let (w0, w1) = (YOSHIDA_W0 as Scalar, YOSHIDA_W1 as Scalar);
+
+        self.drift(w1 / 2. * dt);
+        for (c, d) in [((w0 + w1) / 2., w1), ((w0 + w1) / 2., w0), (w1 / 2., w1)] {
+            let accelerations = self.calc_accelerations();
+            self.kick(&accelerations, d * dt);
+            self.drift(c * dt);
+        }
+        self.reset_accelerations();
+    }
+
+    fn hermite4_step(&mut self, dt: Scalar) {
+        let (a0, j0) = match (self.accelerations.take(), self.jerks.take()) {
+            (Some(a0), Some(j0)) if a0.len() == self.len() && j0.len() == self.len() => (a0, j0),
+            _ => self.calc_accelerations_and_jerks_at(&self.positions, &self.velocities),
+        };
+
+        // Predictor: Taylor series up to the jerk.
+        let mut x_p = Vec::with_capacity(self.len());
+        let mut v_p = Vec::with_capacity(self.len());
+        for i in 0..self.len() {
+            let (x, v) = (self.positions[i], self.velocities[i]);
+            x_p.push(x + v * dt + a0[i] * (dt * dt / 2.) + j0[i] * (dt * dt * dt / 6.));
+            v_p.push(v + a0[i] * dt + j0[i] * (dt * dt / 2.));
+        }
+
+        let (a1, j1) = self.calc_accelerations_and_jerks_at(&x_p, &v_p);
+
+        // Corrector.
+        for i in 0..self.len() {
+            let v0 = self.velocities[i];
+            let v1 = v0 + (a0[i] + a1[i]) * (dt / 2.) + (j0[i] - j1[i]) * (dt * dt / 12.);
+
+            self.positions[i] += (v0 + v1) * (dt / 2.) + (a0[i] - a1[i]) * (dt * dt / 12.);
+            self.velocities[i] = v1;
+        }
+
+        // The accelerations at the predicted state are close enough to be reused.
+        self.accelerations = Some(a1);
+        self.jerks = Some(j1);
+    }
+
+    /// Returns the quadtree of the Barnes-Hut-Algorithm at the current positions,
+    /// which holds every body with its index, the ones without a mass with zero weight.
+    /// It can be used for spatial queries, like Quadtree::nearest.
+    pub fn quadtree(&mut self) -> &Quadtree {
+        let objects = self.objects();
+        self.barnes_hut.quadtree(&objects)
+    }
+
+    /// Returns every pair (i, j) with i < j of bodies that overlap,
+    /// unless the collisions are ignored.
+    /// The quadtree is reused to only compare bodies that are close, see Quadtree::overlapping_pairs.
+    pub fn find_collisions(&mut self) -> Vec<(usize, usize)> {
+        if self.collisions == Collisions::Ignore { return Vec::new() }
+
+        let objects = self.objects();
+        self.barnes_hut.quadtree(&objects).overlapping_pairs(&objects, &self.radii)
+    }
+
+    /// Handles the colliding pairs of bodies (see find_collisions) as configured by the collisions field
+    /// and returns the mergers in the order they happened.
+    /// A body that was merged can absorb further bodies it overlapped with in the same call.
+    /// The absorbed bodies are removed afterwards, see Merger for their indices.
+    pub fn resolve_collisions(&mut self, pairs: &[(usize, usize)]) -> Vec<Merger> {
+        match self.collisions {
+            Collisions::Merge => self.merge_pairs(pairs),
+            Collisions::Bounce => {
+                for &(i, j) in pairs {
+                    self.bounce(i, j);
+                }
+                // Separating the bodies changes their potential energy, so the drift is measured anew like after a merger.
+                if !pairs.is_empty() {
+                    self.reset_accelerations();
+                    self.reset_diagnostics();
+                }
+                Vec::new()
+            }
+            Collisions::Ignore | Collisions::Report => Vec::new(),
+        }
+    }
+
+    /// Is used by resolve_collisions.
+    fn merge_pairs(&mut self, pairs: &[(usize, usize)]) -> Vec<Merger> {
+        let mut mergers = Vec::new();
+        let mut absorbed = vec![false; self.len()];
+
+        for &(i, j) in pairs {
+            if absorbed[i] || absorbed[j] { continue }
+
+            // A previous merger could have moved one of the bodies away.
+            if !collision::overlap(self.positions[i], self.radii[i], self.positions[j], self.radii[j]) { continue }
+
+            // The lighter body is absorbed, with the same mass the one with the larger index.
+            let (survivor, other) = if self.masses[j] > self.masses[i] { (j, i) } else { (i, j) };
+            mergers.push(self.merge(survivor, other));
+            absorbed[other] = true;
+        }
+
+        for i in (0..self.len()).rev().filter(|&i| absorbed[i]) {
+            self.remove_body(i);
+        }
+        mergers
+    }
+
+    /// Merges the absorbed body into the survivor at their center of mass,
+    /// so the linear and the angular momentum are conserved.
+    /// The absorbed body is left unchanged.
+    fn merge(&mut self, survivor: usize, absorbed: usize) -> Merger {
+        let (pos1, vel1, m1) = (self.positions[survivor], self.velocities[survivor], self.masses[survivor]);
+        let (pos2, vel2, m2) = (self.positions[absorbed], self.velocities[absorbed], self.masses[absorbed]);
+        let mass = m1 + m2;
+
+        // Without mass there is no center of mass and no momentum, so the survivor keeps its state.
+        let (position, velocity) = if mass == 0. {
+            (pos1, vel1)
+        } else {
+            ((pos1 * m1 + pos2 * m2) / mass, (vel1 * m1 + vel2 * m2) / mass)
+        };
+
+        // The angular momentum of the two bodies around their center of mass becomes spin.
+        let orbital = m1 * (pos1 - position).cross(vel1 - velocity) + m2 * (pos2 - position).cross(vel2 - velocity);
+        let spin = self.spins[survivor] + self.spins[absorbed] + orbital;
+        let radius = (self.radii[survivor].powi(2) + self.radii[absorbed].powi(2)).sqrt();
+
+        self.positions[survivor] = position;
+        self.velocities[survivor] = velocity;
+        self.masses[survivor] = mass;
+        self.radii[survivor] = radius;
+        self.spins[survivor] = spin;
+
+        Merger { survivor, absorbed, position, velocity, mass, radius, spin }
+    }
+
+    /// Lets the two bodies bounce off each other elastically.
+    /// Only their velocities along the line between their centers change,
+    /// so the linear momentum and the kinetic energy are conserved.
+    /// They are moved apart until they touch, their center of mass stays where it is.
+    /// A body without a mass takes all of the change, two of them take equal parts.
+    fn bounce(&mut self, i: usize, j: usize) {
+        let (m1, m2) = (self.masses[i], self.masses[j]);
+        let offset = self.positions[j] - self.positions[i];
+        let distance = offset.length();
+        // Bodies at the same position are separated along the x axis.
+        let normal = if distance == 0. { Vec2::new(1., 0.) } else { offset / distance };
+
+        // The inverse masses decide how the change is shared.
+        let (w1, w2) = match (m1 == 0., m2 == 0.) {
+            (true, true) => (1., 1.),
+            (true, false) => (1., 0.),
+            (false, true) => (0., 1.),
+            (false, false) => (1. / m1, 1. / m2),
+        };
+
+        // Bodies that already move apart keep their velocities.
+        let approach = (self.velocities[j] - self.velocities[i]).dot(normal).min(0.);
+        let impulse = -2. * approach / (w1 + w2);
+        let overlap = (self.radii[i] + self.radii[j] - distance).max(0.);
+
+        self.velocities[i] -= normal * (impulse * w1);
+        self.velocities[j] += normal * (impulse * w2);
+        self.positions[i] -= normal * (overlap * w1 / (w1 + w2));
+        self.positions[j] += normal * (overlap * w2 / (w1 + w2));
+    }
+
+    /// Changes the velocities by the accelerations over dt.
+    fn kick(&mut self, accelerations: &[Vec2], dt: Scalar) {
+        for (vel, acc) in self.velocities.iter_mut().zip(accelerations) {
+            *vel += *acc * dt;
+        }
+    }
+
+    /// Changes the positions by the velocities over dt.
+    fn drift(&mut self, dt: Scalar) {
+        for (pos, vel) in self.positions.iter_mut().zip(&self.velocities) {
+            *pos += *vel * dt;
+        }
+    }
+}
+
+#[cfg(test)]
+mod simulation_tests {
+    use crate::base::*;
+    use crate::simulation::Simulation;
+    use crate::solver::Algorithm;
+    use crate::integrator::Integrator;
+    use crate::timestep::Timestepping;
+    use crate::collision::Collisions;
+
+    /// A light body on a circular orbit around a heavy body.
+    fn circular_orbit(algorithm: Algorithm) -> Simulation {
+        let mut simulation = Simulation::new(algorithm);
+        let (m, r): (Scalar, Scalar) = (1e18, 400.);
+        let v = (G * m / r).sqrt();
+
+        simulation.add_body(Vec2::new(0., 0.), Vec2::new(0., 0.), m);
+        simulation.add_body(Vec2::new(r, 0.), Vec2::new(0., v), 1.);
+        simulation
+    }
+
+    #[test]
+    fn test_circular_orbit_stays_circular() {
+        for algorithm in [Algorithm::Direct, Algorithm::DirectSimd, Algorithm::BarnesHut, Algorithm::BarnesHutParallel, Algorithm::Fmm] {
+            let mut simulation = circular_orbit(algorithm);
+
+            for _ in 0..1000 {
+                simulation.step(1. / 120.);
+            }
+            let r = (simulation.positions[1] - simulation.positions[0]).length();
+            assert!((r - 400.).abs() < 4., "{algorithm:?}: radius drifted to {r}");
+        }
+    }
+
+    /// Two bodies on an eccentric orbit, similar to two_masses.tscn.
+    fn eccentric_orbit(integrator: Integrator) -> Simulation {
+        let mut simulation = Simulation::new(Algorithm::Direct);
+        simulation.integrator = integrator;
+
+        simulation.add_body(Vec2::new(0., 0.), Vec2::new(0., 0.), 1e18);
+        simulation.add_body(Vec2::new(400., 0.), Vec2::new(0., 350.), 4.4e5);
+        simulation
+    }
+
+    fn energy(simulation: &Simulation) -> Scalar {
+        let (m1, m2) = (simulation.masses[0], simulation.masses[1]);
+        let r = (simulation.positions[1] - simulation.positions[0]).length();
+        let kinetic: Scalar = simulation.velocities.iter().zip(&simulation.masses)
+            .map(|(vel, m)| 0.5 * m * vel.length().powi(2))
+            .sum();
+        kinetic - G * m1 * m2 / r
+    }
+
+    #[test]
+    fn test_symplectic_integrators_keep_the_orbit_closed() {
+        let relative_energy_error = |integrator: Integrator| {
+            let mut simulation = eccentric_orbit(integrator);
+            let start_energy = energy(&simulation);
+
+            for _ in 0..12000 {
+                simulation.step(1. / 120.);
+            }
+            ((energy(&simulation) - start_energy) / start_energy).abs()
+        };
+
+        let euler_error = relative_energy_error(Integrator::SemiImplicitEuler);
+        for integrator in [Integrator::Leapfrog, Integrator::VelocityVerlet] {
+            let error = relative_energy_error(integrator);
+            assert!(error < 1e-3, "{integrator:?}: energy drifted by {error}");
+            assert!(error < euler_error, "{integrator:?} is not more accurate than the Euler method");
+        }
+    }
+
+    #[test]
+    fn test_fourth_order_integrators_have_a_smaller_energy_error() {
+        let relative_energy_error = |integrator: Integrator| {
+            let mut simulation = eccentric_orbit(integrator);
+            let start_energy = energy(&simulation);
+
+            for _ in 0..1000 {
+                simulation.step(1. / 15.);
+            }
+            ((energy(&simulation) - start_energy) / start_energy).abs()
+        };
+
+        let leapfrog_error = relative_energy_error(Integrator::Leapfrog);
+        for integrator in [Integrator::RungeKutta4, Integrator::Yoshida4, Integrator::Hermite4] {
+            let error = relative_energy_error(integrator);
+            assert!(error < leapfrog_error, "{integrator:?}: {error} is not smaller than {leapfrog_error}");
+        }
+    }
+
+    #[test]
+    fn test_leapfrog_and_velocity_verlet_agree() {
+        let mut leapfrog = eccentric_orbit(Integrator::Leapfrog);
+        let mut velocity_verlet = eccentric_orbit(Integrator::VelocityVerlet);
+
+        for _ in 0..1000 {
+            leapfrog.step(1. / 120.);
+            velocity_verlet.step(1. / 120.);
+        }
+        let distance = (leapfrog.positions[1] - velocity_verlet.positions[1]).length();
+        assert!(distance < 1., "the trajectories differ by {distance}");
+    }
+
+    #[test]
+    fn test_adaptive_timesteps_are_more_accurate_than_fixed_timesteps() {
+        let relative_energy_error = |mode: Timestepping| {
+            let mut simulation = eccentric_orbit(Integrator::Leapfrog);
+            simulation.timestep.mode = mode;
+            let start_energy = energy(&simulation);
+
+            for _ in 0..100 {
+                simulation.advance(1. / 2.);
+            }
+            ((energy(&simulation) - start_energy) / start_energy).abs()
+        };
+
+        let fixed_error = relative_energy_error(Timestepping::Fixed);
+        for mode in [Timestepping::Adaptive, Timestepping::Block] {
+            let error = relative_energy_error(mode);
+            assert!(error < fixed_error, "{mode:?}: {error} is not smaller than {fixed_error}");
+        }
+    }
+
+    #[test]
+    fn test_adaptive_timesteps_work_with_every_integrator() {
+        for integrator in [
+            Integrator::SemiImplicitEuler, Integrator::Leapfrog, Integrator::VelocityVerlet,
+            Integrator::RungeKutta4, Integrator::Yoshida4, Integrator::Hermite4,
+        ] {
+            let mut simulation = eccentric_orbit(integrator);
+            simulation.timestep.mode = Timestepping::Adaptive;
+            let start_energy = energy(&simulation);
+
+            for _ in 0..10 {
+                simulation.advance(1. / 2.);
+            }
+            assert_eq!(simulation.levels().len(), simulation.len(), "{integrator:?}");
+            let error = ((energy(&simulation) - start_energy) / start_energy).abs();
+            // The first order Euler method is much less accurate than the others.
+            assert!(error < 1e-1, "{integrator:?}: energy drifted by {error}");
+        }
+    }
+
+    #[test]
+    fn test_block_timesteps_substep_only_the_close_pair() {
+        let mut simulation = Simulation::new(Algorithm::Direct);
+        simulation.timestep.mode = Timestepping::Block;
+
+        // A tight binary ...
+        simulation.add_body(Vec2::new(0., 0.), Vec2::new(0., -20.), 1e16);
+        simulation.add_body(Vec2::new(10., 0.), Vec2::new(0., 20.), 1e16);
+        // ... and a distant body.
+        simulation.add_body(Vec2::new(100000., 0.), Vec2::new(0., 0.), 1e16);
+
+        for _ in 0..10 {
+            simulation.advance(1. / 60.);
+        }
+        let levels = simulation.levels();
+        assert!(levels[0] > levels[2] && levels[1] > levels[2], "levels: {levels:?}");
+
+        // The simulation stays synchronised with the frames.
+        assert!((simulation.time - 10. / 60.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_bodies_start_on_the_required_level() {
+        let mut simulation = Simulation::new(Algorithm::Direct);
+        simulation.timestep.mode = Timestepping::Block;
+
+        simulation.add_body(Vec2::new(0., 0.), Vec2::new(0., -20.), 1e16);
+        simulation.add_body(Vec2::new(10., 0.), Vec2::new(0., 20.), 1e16);
+        simulation.add_body(Vec2::new(100000., 0.), Vec2::new(0., 0.), 1e16);
+
+        // Only the close pair starts on a fine level, the distant body advances with the whole frame.
+        simulation.init_levels(1. / 60.);
+        let levels = simulation.levels().to_vec();
+        assert!(levels[0] > 0 && levels[1] > 0 && levels[2] == 0, "levels: {levels:?}");
+        assert!(levels.iter().all(|&level| level <= simulation.timestep.max_level));
+
+        // The levels come from the selected solver, which agrees with the direct approach.
+        for algorithm in [Algorithm::BarnesHut, Algorithm::Fmm] {
+            simulation.algorithm = algorithm;
+            simulation.levels.clear();
+            simulation.init_levels(1. / 60.);
+            assert_eq!(simulation.levels(), levels, "{algorithm:?}");
+        }
+    }
+
+    #[test]
+    fn test_softening_prevents_ejections() {
+        for kernel in [SofteningKernel::Plummer, SofteningKernel::Spline] {
+            for algorithm in [Algorithm::Direct, Algorithm::BarnesHut, Algorithm::Fmm] {
+                let mut simulation = Simulation::new(algorithm);
+                simulation.softening = Softening::new(kernel, 10.);
+
+                // Two masses falling straight into each other.
+                simulation.add_body(Vec2::new(-100., 0.), Vec2::new(0., 0.), 1e16);
+                simulation.add_body(Vec2::new(100., 0.), Vec2::new(0., 0.), 1e16);
+
+                for _ in 0..2000 {
+                    simulation.step(1. / 120.);
+                }
+                let distance = (simulation.positions[1] - simulation.positions[0]).length();
+                assert!(distance <= 200.1, "{kernel:?} {algorithm:?}: the masses were ejected to {distance}");
+            }
+        }
+    }
+
+    /// An orbit of the earth around the sun in SI units needs f64.
+    #[cfg(feature = "f64")]
+    #[test]
+    fn test_astronomical_scales_keep_precision() {
+        let mut simulation = Simulation::new(Algorithm::Direct);
+        let (m, r) = (1.989e30, 1.496e11);
+        let v = (G * m / r).sqrt();
+
+        simulation.add_body(Vec2::new(0., 0.), Vec2::new(0., 0.), m);
+        simulation.add_body(Vec2::new(r, 0.), Vec2::new(0., v), 5.972e24);
+
+        // A year in steps of a day.
+        for _ in 0..365 {
+            simulation.step(86400.);
+        }
+        let distance = (simulation.positions[1] - simulation.positions[0]).length();
+        assert!((distance - r).abs() < 1e-3 * r);
+    }
+
+    #[test]
+    fn test_massless_body_is_not_accelerated_to_nan() {
+        let mut simulation = Simulation::default();
+        simulation.add_body(Vec2::new(0., 0.), Vec2::new(0., 0.), 0.);
+        simulation.add_body(Vec2::new(1., 0.), Vec2::new(0., 0.), 1.);
+
+        simulation.step(1.);
+        assert!(simulation.positions.iter().all(|pos| pos.x.is_finite() && pos.y.is_finite()));
+    }
+
+    #[test]
+    fn test_mergers_conserve_momentum() {
+        let mut simulation = Simulation { collisions: Collisions::Merge, ..Default::default() };
+
+        simulation.add_body_with_radius(Vec2::new(0., 0.), Vec2::new(1., 2.), 3., 10.);
+        simulation.add_body_with_radius(Vec2::new(100., 0.), Vec2::new(0., 0.), 1., 10.);
+        simulation.add_body_with_radius(Vec2::new(12., 5.), Vec2::new(-4., 3.), 2., 5.);
+        simulation.add_body_with_radius(Vec2::new(8., -3.), Vec2::new(0., -1.), 1., 0.);
+
+        let momentum = |simulation: &Simulation| -> (Vec2, Scalar) {
+            let mut linear = Vec2::new(0., 0.);
+            let mut angular = 0.;
+            for i in 0..simulation.len() {
+                let (pos, vel, m) = (simulation.positions[i], simulation.velocities[i], simulation.masses[i]);
+                linear += vel * m;
+                angular += m * pos.cross(vel) + simulation.spins[i];
+            }
+            (linear, angular)
+        };
+        let (linear, angular) = momentum(&simulation);
+
+        let pairs = simulation.find_collisions();
+        let mergers = simulation.resolve_collisions(&pairs);
+        // The first body absorbs the third and the fourth body, the second body is too far away.
+        assert_eq!(mergers.iter().map(|merger| (merger.survivor, merger.absorbed)).collect::<Vec<_>>(), [(0, 2), (0, 3)]);
+        assert_eq!(simulation.len(), 2);
+        assert_eq!(simulation.masses, [6., 1.]);
+        assert!((simulation.radii[0] - (125. as Scalar).sqrt()).abs() < 1e-4);
+
+        let (new_linear, new_angular) = momentum(&simulation);
+        assert!((new_linear - linear).length() < 1e-4);
+        assert!((new_angular - angular).abs() < 1e-3);
+        assert!(simulation.spins[0] != 0.);
+
+        // Without collisions the bodies pass through each other.
+        simulation.collisions = Collisions::Ignore;
+        simulation.positions[1] = simulation.positions[0];
+        assert!(simulation.find_collisions().is_empty());
+    }
+
+    #[test]
+    fn test_diagnostics_track_the_drift() {
+        let mut simulation = eccentric_orbit(Integrator::Leapfrog);
+        simulation.diagnose = true;
+        assert!(simulation.diagnostics().is_none());
+
+        let diagnostics = simulation.calc_diagnostics();
+        assert!((diagnostics.total_energy() - energy(&simulation)).abs() < 1e-4 * energy(&simulation).abs());
+        assert!((diagnostics.mass - (1e18 + 4.4e5)).abs() < 1e12);
+        assert!((diagnostics.angular_momentum - 4.4e5 * 400. * 350.).abs() < 1e-4 * diagnostics.angular_momentum);
+
+        for _ in 0..1000 {
+            simulation.advance(1. / 120.);
+        }
+        let drift = simulation.diagnostics_drift().unwrap();
+        assert!(drift.energy < 1e-3, "energy drift: {}", drift.energy);
+        assert!(drift.momentum < 1e-3, "momentum drift: {}", drift.momentum);
+        assert!(drift.angular_momentum < 1e-3, "angular momentum drift: {}", drift.angular_momentum);
+
+        // The drift is measured again after the bodies changed.
+        simulation.add_body(Vec2::new(1000., 0.), Vec2::new(0., 0.), 1.);
+        assert!(simulation.diagnostics_drift().is_none());
+        simulation.advance(1. / 120.);
+        assert_eq!(simulation.diagnostics_drift().unwrap().energy, 0.);
+    }
+
+    #[test]
+    fn test_bounces_conserve_momentum_and_energy() {
+        let mut simulation = Simulation { collisions: Collisions::Bounce, ..Default::default() };
+
+        simulation.add_body_with_radius(Vec2::new(0., 0.), Vec2::new(3., 1.), 2., 10.);
+        simulation.add_body_with_radius(Vec2::new(15., 5.), Vec2::new(-1., 0.), 1., 10.);
+
+        let momentum = |simulation: &Simulation| simulation.velocities[0] * simulation.masses[0] + simulation.velocities[1] * simulation.masses[1];
+        let kinetic_energy = |simulation: &Simulation| -> Scalar {
+            simulation.velocities.iter().zip(&simulation.masses).map(|(vel, m)| 0.5 * m * vel.dot(*vel)).sum()
+        };
+        let center_of_mass = |simulation: &Simulation| (simulation.positions[0] * 2. + simulation.positions[1]) / 3.;
+        let (start_momentum, start_energy, start_center) = (momentum(&simulation), kinetic_energy(&simulation), center_of_mass(&simulation));
+
+        let pairs = simulation.find_collisions();
+        assert_eq!(pairs, [(0, 1)]);
+        assert!(simulation.resolve_collisions(&pairs).is_empty());
+
+        assert!((momentum(&simulation) - start_momentum).length() < 1e-4);
+        assert!((kinetic_energy(&simulation) - start_energy).abs() < 1e-4);
+        assert!((center_of_mass(&simulation) - start_center).length() < 1e-4);
+
+        // The bodies touch and move apart.
+        let offset = simulation.positions[1] - simulation.positions[0];
+        assert!((offset.length() - 20.).abs() < 1e-3);
+        assert!((simulation.velocities[1] - simulation.velocities[0]).dot(offset) > 0.);
+        assert!(simulation.find_collisions().is_empty());
+    }
+
+    #[test]
+    fn test_bounces_reset_the_drift_reference() {
+        let mut simulation = Simulation { collisions: Collisions::Bounce, ..Default::default() };
+
+        simulation.add_body_with_radius(Vec2::new(0., 0.), Vec2::new(0., 0.), 1e14, 10.);
+        simulation.add_body_with_radius(Vec2::new(5., 0.), Vec2::new(0., 0.), 1e14, 10.);
+        simulation.update_diagnostics();
+
+        // Separating the bodies raises their potential energy, which is not a drift.
+        let pairs = simulation.find_collisions();
+        assert!(simulation.resolve_collisions(&pairs).is_empty());
+        assert!(simulation.diagnostics_drift().is_none());
+
+        simulation.update_diagnostics();
+        assert_eq!(simulation.diagnostics_drift().unwrap().energy, 0.);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::base::{Object, Object3D, Vec2, Vec3};
+
+/// An algorithm that calculates the gravitational forces between objects.
+/// Every algorithm is compiled in, so the used one can be switched at runtime.
+pub trait GravitySolver {
+    /// Returns the total force that acts on every object,
+    /// in the same order as the objects.
+    fn calc_forces(&mut self, objects: &[Object]) -> Vec<Vec2>;
+
+    /// Returns the total force that acts on each of the targets,
+    /// which are indices into the objects.
+    /// Solvers can override this to only do the work for the targets.
+    fn calc_forces_on(&mut self, objects: &[Object], targets: &[usize]) -> Vec<Vec2> {
+        let forces = self.calc_forces(objects);
+        targets.iter().map(|&i| forces[i]).collect()
+    }
+}
+
+/// The 3D counterpart of GravitySolver.
+pub trait GravitySolver3D {
+    /// Returns the total force that acts on every object,
+    /// in the same order as the objects.
+    fn calc_forces_3d(&mut self, objects: &[Object3D]) -> Vec<Vec3>;
+}
+
+/// The available gravity algorithms.
+/// With the godot feature it can be exported as a property.
+#[cfg_attr(feature = "godot", derive(godot::prelude::Property, godot::prelude::Export))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(i64)]
+pub enum Algorithm {
+    Direct = 0,
+    #[default]
+    BarnesHut = 1,
+    BarnesHutParallel = 2,
+    Fmm = 3,
+    /// The direct approach with the vectorized kernel, which visits every pair twice, see direct::get_forces_simd.
+    DirectSimd = 4,
+}
+
+/// The gravity algorithms that are available in 3D.
+/// With the godot feature it can be exported as a property.
+#[cfg_attr(feature = "godot", derive(godot::prelude::Property, godot::prelude::Export))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(i64)]
+pub enum Algorithm3D {
+    Direct = 0,
+    #[default]
+    BarnesHut = 1,
+}
+
+#[cfg(test)]
+mod solver_tests {
+    use crate::solver::GravitySolver;
+    use crate::direct::Direct;
+    use crate::barnes_hut::BarnesHut;
+    use crate::fmm::Fmm;
+    use crate::tests::objects;
+
+    #[test]
+    fn test_solvers_agree() {
+        let objects = objects(50, 101., 97., |i| 1e10 + i);
+
+        let exact = Direct::default().calc_forces(&objects);
+
+        let solvers: Vec<Box<dyn GravitySolver>> = vec![
+            Box::new(BarnesHut::new(0., false)),
+            Box::new(BarnesHut::new(0., true)),
+            Box::new(Fmm::default()),
+        ];
+        for mut solver in solvers {
+            let forces = solver.calc_forces(&objects);
+            assert_eq!(forces.len(), objects.len());
+
+            for (force, exact_force) in forces.iter().zip(&exact) {
+                assert!((*force - *exact_force).length() <= 1e-3 * exact_force.length());
+            }
+        }
+    }
+}
@@ -1,189 +1,414 @@
-#[allow(unused_imports)]
-use crate::base::{Scalar, Object, Vec2};
-
-#[cfg(feature = "direct")]
-use crate::direct::apply_forces;
-
-#[cfg(feature = "barnes_hut")]
-use crate::barnes_hut::{Quadtree, Cell, THETA};
-
-#[allow(unused_imports)]
-use godot::engine::RigidBody2D;
+use crate::base::{Scalar, Softening, SofteningKernel};
+use crate::solver::Algorithm;
+use crate::integrator::Integrator;
+use crate::timestep::{self, Timestepping};
+use crate::simulation::Simulation;
+use crate::collision::{Collisions, Merger};
+use crate::barnes_hut::{self, AcceptanceCriterion, Multipoles};
+use crate::fmm;
+
+use godot::engine::{RigidBody2D, rigid_body_2d::FreezeMode};
 use godot::prelude::*;
 
-#[cfg(feature = "barnes_hut_parallel_force_calc")]
-use std::{thread, sync::{Mutex, Arc}};
-
 // time testing
 use std::time::Instant;
 
+/// Connects the simulation to the Godot scene.
+/// Every child that is a Mass takes part in the simulation.
 #[derive(GodotClass)]
 #[class(base=Node2D)]
 struct Space {
     #[base]
     node2d: Base<Node2D>,
 
-    #[cfg(feature = "barnes_hut")]
+    /// Can be switched while the simulation is running.
+    #[export]
+    algorithm: Algorithm,
+
+    /// The accuracy θ of the Barnes-Hut-Algorithm.
+    /// The RelativeForce criterion uses it until the first accelerations are known.
     #[export]
     theta: f32,
+
+    /// Decides which nodes of the Barnes-Hut-Algorithm are far enough away to be used as a whole.
+    #[export]
+    criterion: AcceptanceCriterion,
+
+    /// The relative force error α of the RelativeForce criterion.
+    #[export]
+    alpha: f32,
+
+    /// The quadrupole moments allow a larger θ for the same accuracy.
+    #[export]
+    multipoles: Multipoles,
+
+    /// The number of threads of the parallel Barnes-Hut-Algorithm, 0 uses one thread per core.
+    #[export]
+    threads: u32,
+
+    /// Walks the Barnes-Hut quadtree once per leaf for all of its masses instead of once per mass.
+    #[export]
+    grouped: bool,
+
+    /// The Barnes-Hut quadtree is only rebuilt every this many force calculations and refit in between.
+    #[export]
+    rebuild_interval: u32,
+
+    /// The number of masses a leaf of the Barnes-Hut quadtree holds before it is subdivided.
+    #[export]
+    bucket_size: u32,
+
+    /// The depth of the Barnes-Hut quadtree is bounded, so masses at the same position do not split it up indefinitely.
+    #[export]
+    max_depth: u32,
+
+    /// The space around the masses the Barnes-Hut quadtree leaves, relative to their extent,
+    /// so it has to grow less often while it is refit.
+    #[export]
+    padding: f32,
+
+    /// The separation criterion of the Fast-Multipole-Method.
+    #[export]
+    fmm_theta: f32,
+
+    /// The number of terms per index of the multipole expansions.
+    #[export]
+    order: u32,
+
+    /// Softens the force between close masses, so they are not ejected.
+    #[export]
+    softening_kernel: SofteningKernel,
+
+    /// The softening length ε, applied to all algorithms.
+    #[export]
+    softening_length: f32,
+
+    /// The integrator the simulation uses to move the masses.
+    #[export]
+    integrator: Integrator,
+
+    /// How every physics frame is divided into timesteps.
+    #[export]
+    timestepping: Timestepping,
+
+    /// The accuracy parameter η of the adaptive timesteps.
+    #[export]
+    eta: f32,
+
+    /// A frame is divided into at most 2^max_level timesteps.
+    /// It is capped at timestep::LEVEL_LIMIT (12), larger values would stall the game.
+    #[export]
+    max_level: u32,
+
+    /// Handles masses whose collision circles overlap, see Simulation::resolve_collisions.
+    /// Only used when the simulation integrates the masses,
+    /// otherwise Godots physics engine handles the collisions.
+    /// Merges by default, like the masses did before the simulation integrated them.
+    /// The integrated masses are frozen and Godots physics engine does not report contacts between them,
+    /// so with Ignore they pass through each other.
+    #[export]
+    collisions: Collisions,
+
+    /// Lets Godots physics engine integrate the gravitational forces instead of the simulation.
+    /// Otherwise the masses are frozen and their positions and velocities are set by the simulation.
+    #[export]
+    godot_integration: bool,
+
+    /// Calculates the diagnostics below every frame.
+    /// The drift is only tracked while the simulation integrates the masses,
+    /// otherwise it is reloaded every frame.
+    #[export]
+    diagnose: bool,
+
+    /// The total kinetic energy of the masses.
+    #[var(get)]
+    kinetic_energy: f64,
+
+    /// The total potential energy of the masses, with the softening applied.
+    #[var(get)]
+    potential_energy: f64,
+
+    #[var(get)]
+    total_energy: f64,
+
+    /// The total linear momentum of the masses.
+    #[var(get)]
+    momentum: Vector2,
+
+    /// The total angular momentum of the masses around the origin.
+    #[var(get)]
+    angular_momentum: f64,
+
+    #[var(get)]
+    center_of_mass: Vector2,
+
+    /// The relative change of the total energy since masses were added or removed.
+    #[var(get)]
+    energy_drift: f64,
+
+    /// The relative change of the momentum, see energy_drift.
+    #[var(get)]
+    momentum_drift: f64,
+
+    /// The relative change of the angular momentum, see energy_drift.
+    #[var(get)]
+    angular_momentum_drift: f64,
+
+    simulation: Simulation,
+
+    /// The masses in the same order as the bodies of the simulation.
+    bodies: Vec<Gd<RigidBody2D>>,
 }
 
-// Needed, otherwise the #[export] wont work.
-#[cfg(feature = "barnes_hut")]
 #[godot_api]
-impl Space {}
+impl Space {
+    /// Is emitted for every pair of masses that overlap, before the collision is handled.
+    #[signal]
+    fn collided(body1: Gd<RigidBody2D>, body2: Gd<RigidBody2D>);
+
+    /// Is emitted after the survivor absorbed the other mass, which is freed afterwards.
+    /// The survivor already has the merged position, velocity and mass.
+    /// Its collision shape is not resized, the radius of the merged mass is passed instead.
+    #[signal]
+    fn merged(survivor: Gd<RigidBody2D>, absorbed: Gd<RigidBody2D>, radius: f64);
+
+    /// Returns the masses inside the rectangle at their positions of the last frame.
+    /// Masses with a mass of 0 are found as well.
+    #[func]
+    fn bodies_in_rect(&mut self, rect: Rect2) -> Array<Gd<RigidBody2D>> {
+        let (min, max) = (rect.position.into(), (rect.position + rect.size).into());
+        let bodies = self.simulation.quadtree().bodies_in_rect(min, max);
+        self.to_array(bodies)
+    }
+
+    /// Returns the masses at most the radius away from the center, see bodies_in_rect.
+    #[func]
+    fn bodies_in_radius(&mut self, center: Vector2, radius: f64) -> Array<Gd<RigidBody2D>> {
+        let bodies = self.simulation.quadtree().bodies_in_radius(center.into(), radius as Scalar);
+        self.to_array(bodies)
+    }
+
+    /// Returns the k masses closest to the point, the closest first, see bodies_in_rect.
+    #[func]
+    fn nearest_bodies(&mut self, point: Vector2, k: i64) -> Array<Gd<RigidBody2D>> {
+        let bodies = self.simulation.quadtree().nearest(point.into(), k.max(0) as usize);
+        self.to_array(bodies)
+    }
+}
 
 #[godot_api]
 impl INode2D for Space {
-    #[cfg(not(feature = "barnes_hut"))]
     fn init(node2d: Base<Node2D>) -> Self {
-        Self { node2d }
+        Self {
+            node2d,
+            algorithm: Algorithm::default(),
+            theta: barnes_hut::THETA as f32,
+            criterion: AcceptanceCriterion::default(),
+            alpha: barnes_hut::ALPHA as f32,
+            multipoles: Multipoles::default(),
+            threads: 0,
+            grouped: barnes_hut::GROUPED,
+            rebuild_interval: barnes_hut::REBUILD_INTERVAL as u32,
+            bucket_size: barnes_hut::BUCKET_SIZE as u32,
+            max_depth: barnes_hut::MAX_DEPTH as u32,
+            padding: barnes_hut::PADDING as f32,
+            fmm_theta: fmm::THETA as f32,
+            order: fmm::ORDER as u32,
+            softening_kernel: SofteningKernel::default(),
+            softening_length: 0.,
+            integrator: Integrator::default(),
+            timestepping: Timestepping::default(),
+            eta: timestep::ETA as f32,
+            max_level: timestep::MAX_LEVEL,
+            collisions: Collisions::Merge,
+            godot_integration: false,
+            diagnose: false,
+            kinetic_energy: 0.,
+            potential_energy: 0.,
+            total_energy: 0.,
+            momentum: Vector2::ZERO,
+            angular_momentum: 0.,
+            center_of_mass: Vector2::ZERO,
+            energy_drift: 0.,
+            momentum_drift: 0.,
+            angular_momentum_drift: 0.,
+            simulation: Simulation::default(),
+            bodies: Vec::new(),
+        }
     }
 
-    #[cfg(feature = "barnes_hut")]
-    fn init(node2d: Base<Node2D>) -> Self {
-        Self { node2d, theta: THETA }
-    }
+    fn physics_process(&mut self, delta: f64) {
+        let start = Instant::now();
 
+        self.sync_properties();
 
-    // If no algorithm is specified as a feature, a warning is thrown
-    #[cfg(not(any(feature = "direct", feature = "barnes_hut", feature = "fmm")))]
-    fn physics_process(&mut self, _delta: f64) {
-        let message = "Compiled with no algorithm specified.\n\
-            Specify an algorithm with --features <algorithm>.\n\
-            Look at the Cargo.toml for available the algorithms.";
+        if self.godot_integration {
+            self.apply_forces();
+        } else {
+            self.integrate(delta as Scalar);
+        }
 
-        godot_print!("{}", message);
-        godot_warn!("{}", message);
+        if self.diagnose {
+            self.update_diagnostics();
+        }
+
+        godot_print!("gravity total time ({:?}): {}ms", self.algorithm, start.elapsed().as_millis());
     }
+}
 
-    #[cfg(feature = "direct")]
-    fn physics_process(&mut self, _delta: f64) {
-        let start = Instant::now();
+impl Space {
+    /// Copies the exported properties into the simulation.
+    fn sync_properties(&mut self) {
+        self.simulation.algorithm = self.algorithm;
+        self.simulation.softening = Softening::new(self.softening_kernel, self.softening_length as Scalar);
+        self.simulation.integrator = self.integrator;
+        self.simulation.timestep.mode = self.timestepping;
+        self.simulation.timestep.eta = self.eta as Scalar;
+        self.simulation.timestep.max_level = self.max_level.min(timestep::LEVEL_LIMIT);
+        self.simulation.barnes_hut.theta = self.theta as Scalar;
+        self.simulation.barnes_hut.threads = self.threads as usize;
+        self.simulation.barnes_hut.grouped = self.grouped;
+        self.simulation.barnes_hut.rebuild_interval = self.rebuild_interval as usize;
+        self.simulation.barnes_hut.bucket_size = self.bucket_size as usize;
+        self.simulation.barnes_hut.max_depth = self.max_depth as usize;
+        self.simulation.barnes_hut.padding = self.padding as Scalar;
+        self.simulation.barnes_hut.multipoles = self.multipoles;
+        self.simulation.barnes_hut.criterion = self.criterion;
+        self.simulation.barnes_hut.alpha = self.alpha as Scalar;
+        self.simulation.fmm.theta = self.fmm_theta as Scalar;
+        self.simulation.fmm.order = self.order as usize;
+        self.simulation.collisions = self.collisions;
+    }
 
-        // The godot representation of masses
-        let mut bodies = Vec::<Gd<RigidBody2D>>::new();
-        // The custom representation of masses
-        let mut objects = Vec::<Object>::new();
+    /// Calculates the diagnostics of the simulation and copies them into the properties.
+    fn update_diagnostics(&mut self) {
+        let diagnostics = self.simulation.update_diagnostics();
+        let drift = self.simulation.diagnostics_drift().unwrap_or_default();
+
+        self.kinetic_energy = diagnostics.kinetic_energy as f64;
+        self.potential_energy = diagnostics.potential_energy as f64;
+        self.total_energy = diagnostics.total_energy() as f64;
+        self.momentum = diagnostics.momentum.into();
+        self.angular_momentum = diagnostics.angular_momentum as f64;
+        self.center_of_mass = diagnostics.center_of_mass.into();
+        self.energy_drift = drift.energy as f64;
+        self.momentum_drift = drift.momentum as f64;
+        self.angular_momentum_drift = drift.angular_momentum as f64;
+    }
 
-        for child in self.node2d.get_children().iter_shared() {
-            if child.get("resource_name".into()).to_string() == "Mass" {
-                // "." is the current node
-                let rigid_body2d = child.try_get_node_as::<RigidBody2D>(".").unwrap();                
-                
-                objects.push(Object::copy_from_rigidbody(&rigid_body2d));
-                bodies.push(rigid_body2d);
-            }
+    /// Returns the masses of the indices.
+    fn to_array(&self, indices: Vec<usize>) -> Array<Gd<RigidBody2D>> {
+        let mut array = Array::new();
+        for i in indices {
+            array.push(self.bodies[i].clone());
         }
-        apply_forces(&objects, |i, force| {
-            bodies[i].apply_force(force.into());
-        });
-
-        godot_print!("gravity force time: {}ms", start.elapsed().as_millis());
+        array
     }
 
-    #[cfg(feature = "barnes_hut")]
-    fn physics_process(&mut self, _delta: f64) {
-        let start = Instant::now();
-
-        // The godot representation of masses
+    /// Returns all children that are masses.
+    fn collect_bodies(&self) -> Vec<Gd<RigidBody2D>> {
         let mut bodies = Vec::<Gd<RigidBody2D>>::new();
-        // The custom representation of masses
-        let mut objects = Vec::<Object>::new();
-
-        // uses the same steps as calc_tree_box in Quadtree,
-        // but by using the for loop that is already needed,
-        // we can save a little time.
-        let mut smallest_x = 0.;
-        let mut smallest_y = 0.;
-        let mut largest_x = 0.;
-        let mut largest_y = 0.;
-        let mut first_body = true;
 
         for child in self.node2d.get_children().iter_shared() {
             if child.get("resource_name".into()).to_string() == "Mass" {
                 // "." is the current node
-                let rigid_body2d = child.try_get_node_as::<RigidBody2D>(".").unwrap();                
-                let pos: Vec2 = rigid_body2d.get_position().into();
-                
-                objects.push(Object::copy_from_rigidbody(&rigid_body2d));
-                bodies.push(rigid_body2d);
-
-                if first_body {
-                    smallest_x = pos.x; smallest_y = pos.y;
-                    largest_x = pos.x; largest_y = pos.y;
-                    first_body = false;
-                    continue;
-                }
-
-                if pos.x < smallest_x { smallest_x = pos.x }
-                else if pos.x > largest_x { largest_x = pos.x }
-
-                if pos.y < smallest_y { smallest_y = pos.y }
-                else if pos.y > largest_y { largest_y = pos.y }
+                bodies.push(child.try_get_node_as::<RigidBody2D>(".").unwrap());
             }
         }
+        bodies
+    }
 
-        let size = largest_x.max(largest_y) - smallest_x.min(smallest_y);
-        let cell = Cell::new(smallest_x, smallest_y, size);
+    /// Rebuilds the simulation from the state of the masses.
+    fn reload_bodies(&mut self, bodies: Vec<Gd<RigidBody2D>>) {
+        self.simulation.clear();
+        for body in &bodies {
+            self.simulation.add_rigidbody(body);
+        }
+        self.bodies = bodies;
+    }
 
-        let qtree = Quadtree::create_from_objects(&objects, cell);
+    /// Calculates the forces and lets Godots physics engine apply them.
+    fn apply_forces(&mut self) {
+        let bodies = self.collect_bodies();
+        self.reload_bodies(bodies);
 
-        godot_print!("gravity force build_tree: {}ms", start.elapsed().as_millis());
+        let forces = self.simulation.calc_forces();
 
-        let force_calc_start = Instant::now();
+        for (body, force) in self.bodies.iter_mut().zip(forces) {
+            body.set_freeze_enabled(false);
+            body.apply_force(force.into());
+        }
+    }
 
-        barnes_hut_calc_forces(qtree, objects, bodies, self.theta);
+    /// Advances the simulation by the frame and writes the new positions and velocities back to the masses.
+    /// The simulation keeps its state between the steps,
+    /// it is only reloaded from the masses when masses are added or removed.
+    fn integrate(&mut self, delta: Scalar) {
+        let bodies = self.collect_bodies();
+
+        let unchanged = bodies.len() == self.bodies.len()
+            && bodies.iter().zip(&self.bodies).all(|(body, old)| body.instance_id() == old.instance_id());
+
+        if unchanged {
+            // The masses can still be changed by scripts.
+            let mut masses_changed = false;
+            for (m, body) in self.simulation.masses.iter_mut().zip(&self.bodies) {
+                let new_m = body.get_mass() as Scalar;
+                masses_changed |= *m != new_m;
+                *m = new_m;
+            }
+            if masses_changed {
+                self.simulation.reset_accelerations();
+                self.simulation.reset_diagnostics();
+            }
+        } else {
+            self.reload_bodies(bodies);
+        }
 
-        godot_print!("gravity force time: {}ms", force_calc_start.elapsed().as_millis());
+        self.simulation.advance(delta);
 
-        godot_print!("gravity total time: {}ms", start.elapsed().as_millis());
-    }
-
-    #[cfg(feature = "fmm")]
-    fn physics_process(&mut self, _delta: f64) {
-        godot_print!("Not yet implemented");
-    }
+        let pairs = self.simulation.find_collisions();
+        for &(i, j) in &pairs {
+            let args = [self.bodies[i].to_variant(), self.bodies[j].to_variant()];
+            self.node2d.emit_signal("collided".into(), &args);
+        }
+        let mergers = self.simulation.resolve_collisions(&pairs);
+        self.apply_mergers(&mergers);
 
-}
+        let simulation = &self.simulation;
+        for ((body, pos), vel) in self.bodies.iter_mut().zip(&simulation.positions).zip(&simulation.velocities) {
+            // Frozen bodies are not moved by Godots physics engine.
+            body.set_freeze_mode(FreezeMode::KINEMATIC);
+            body.set_freeze_enabled(true);
 
-#[cfg(all(feature = "barnes_hut", not(feature = "barnes_hut_parallel_force_calc")))]
-fn barnes_hut_calc_forces(qtree: Quadtree, objects: Vec<Object>, mut bodies: Vec<Gd<RigidBody2D>>, theta: Scalar)  {
-    for (i, body) in bodies.iter_mut().enumerate() {
-        let force = qtree.calc_force(objects[i], theta);
-        body.apply_force(force.into());
+            body.set_position((*pos).into());
+            body.set_linear_velocity((*vel).into());
+        }
     }
-}
 
-/// This is probably inefficient!
-#[cfg(feature = "barnes_hut_parallel_force_calc")]
-fn barnes_hut_calc_forces(qtree: Quadtree, mut objects: Vec<Object>, mut bodies: Vec<Gd<RigidBody2D>>, theta: Scalar)  {
-    // Splits the array of objects into two arrays
-    let objects2 = objects.split_off(objects.len() / 2);
+    /// Gives the survivors their merged state, frees the absorbed masses
+    /// and removes them from the bodies, like the simulation already did.
+    fn apply_mergers(&mut self, mergers: &[Merger]) {
+        for merger in mergers {
+            let mut survivor = self.bodies[merger.survivor].clone();
+            let mut absorbed = self.bodies[merger.absorbed].clone();
 
-    let forces2_arc = Arc::new(Mutex::new(Vec::<Vec2>::new()));
-    let forces2_arc_clone = Arc::clone(&forces2_arc);
-    let qtree2 = qtree.clone();
+            survivor.set_position(merger.position.into());
+            survivor.set_linear_velocity(merger.velocity.into());
+            survivor.set_mass(merger.mass as f32);
 
-    let thread2 = thread::spawn(move || {
-        let mut forces2 = forces2_arc_clone.lock().unwrap();
-        for object2 in objects2 {
-            forces2.push(qtree2.calc_force(object2, theta));
-        }
-    });
-
-    // thread1 is the current thread.
-    for (i, object) in objects.iter().enumerate() {
-        let force = qtree.calc_force(*object, theta);
-        bodies[i].apply_force(force.into());
-    }
+            let args = [survivor.to_variant(), absorbed.to_variant(), (merger.radius as f64).to_variant()];
+            self.node2d.emit_signal("merged".into(), &args);
 
-    thread2.join().unwrap();
-    let forces2 = forces2_arc.lock().unwrap();
+            // Removed right away, so it is not collected again before it is freed.
+            self.node2d.remove_child(absorbed.clone().upcast());
+            absorbed.queue_free();
+        }
 
-    for (i, force2) in forces2.iter().enumerate() {
-        let correct_i = i + bodies.len() / 2;
-        bodies[correct_i].apply_force((*force2).into());
+        let mut absorbed: Vec<usize> = mergers.iter().map(|merger| merger.absorbed).collect();
+        absorbed.sort_unstable();
+        for i in absorbed.into_iter().rev() {
+            self.bodies.remove(i);
+        }
     }
 }
-
@@ -0,0 +1,100 @@
+use crate::base::{Object3D, Scalar, Softening, SofteningKernel};
+use crate::solver::{Algorithm3D, GravitySolver3D};
+use crate::direct::Direct;
+use crate::barnes_hut::{self, BarnesHut};
+
+use godot::engine::RigidBody3D;
+use godot::prelude::*;
+
+// time testing
+use std::time::Instant;
+
+/// The 3D counterpart of the Space node.
+/// Every child that is a Mass takes part in the simulation,
+/// the forces are integrated by Godots physics engine.
+#[derive(GodotClass)]
+#[class(base=Node3D)]
+struct Space3D {
+    #[base]
+    node3d: Base<Node3D>,
+
+    /// Can be switched while the simulation is running.
+    #[export]
+    algorithm: Algorithm3D,
+
+    /// The accuracy θ of the Barnes-Hut-Algorithm.
+    #[export]
+    theta: f32,
+
+    /// Softens the force between close masses, so they are not ejected.
+    #[export]
+    softening_kernel: SofteningKernel,
+
+    /// The softening length ε.
+    #[export]
+    softening_length: f32,
+
+    direct: Direct,
+    barnes_hut: BarnesHut,
+}
+
+// Needed, otherwise the #[export] wont work.
+#[godot_api]
+impl Space3D {}
+
+#[godot_api]
+impl INode3D for Space3D {
+    fn init(node3d: Base<Node3D>) -> Self {
+        Self {
+            node3d,
+            algorithm: Algorithm3D::default(),
+            theta: barnes_hut::THETA as f32,
+            softening_kernel: SofteningKernel::default(),
+            softening_length: 0.,
+            direct: Direct::default(),
+            barnes_hut: BarnesHut::default(),
+        }
+    }
+
+    fn physics_process(&mut self, _delta: f64) {
+        let start = Instant::now();
+
+        let mut bodies = self.collect_bodies();
+        let objects: Vec<Object3D> = bodies.iter().map(Object3D::copy_from_rigidbody3d).collect();
+
+        let softening = Softening::new(self.softening_kernel, self.softening_length as Scalar);
+        let solver: &mut dyn GravitySolver3D = match self.algorithm {
+            Algorithm3D::Direct => {
+                self.direct.softening = softening;
+                &mut self.direct
+            }
+            Algorithm3D::BarnesHut => {
+                self.barnes_hut.theta = self.theta as Scalar;
+                self.barnes_hut.softening = softening;
+                &mut self.barnes_hut
+            }
+        };
+        let forces = solver.calc_forces_3d(&objects);
+
+        for (body, force) in bodies.iter_mut().zip(forces) {
+            body.apply_central_force(force.into());
+        }
+
+        godot_print!("gravity total time ({:?}): {}ms", self.algorithm, start.elapsed().as_millis());
+    }
+}
+
+impl Space3D {
+    /// Returns all children that are masses.
+    fn collect_bodies(&self) -> Vec<Gd<RigidBody3D>> {
+        let mut bodies = Vec::<Gd<RigidBody3D>>::new();
+
+        for child in self.node3d.get_children().iter_shared() {
+            if child.get("resource_name".into()).to_string() == "Mass" {
+                // "." is the current node
+                bodies.push(child.try_get_node_as::<RigidBody3D>(".").unwrap());
+            }
+        }
+        bodies
+    }
+}
@@ -0,0 +1,60 @@
+use crate::base::{Scalar, Vec2};
+
+/// The default accuracy parameter η of the timestep criterion dt = η * |a| / |ȧ|.
+pub const ETA: Scalar = 0.02;
+
+/// The default maximum level. A body on level k is advanced with the timestep delta / 2^k,
+/// so a frame is split into at most 2^MAX_LEVEL substeps.
+pub const MAX_LEVEL: u32 = 8;
+
+/// The largest maximum level the Space node accepts,
+/// so a physics frame is never split into more than 4096 substeps.
+pub const LEVEL_LIMIT: u32 = 12;
+
+/// How the simulation divides a frame into timesteps.
+/// With the godot feature it can be exported as a property.
+#[cfg_attr(feature = "godot", derive(godot::prelude::Property, godot::prelude::Export))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(i64)]
+pub enum Timestepping {
+    /// Every frame is a single step of the selected integrator.
+    #[default]
+    Fixed = 0,
+    /// The frame is split into equal power-of-two substeps,
+    /// which are as small as the timestep criterion requires for the most demanding body.
+    Adaptive = 1,
+    /// Every body gets its own power-of-two timestep (block timesteps),
+    /// so close pairs are substepped while distant bodies advance in large steps.
+    /// Always uses the kick-drift-kick leapfrog, the selected integrator is ignored.
+    Block = 2,
+}
+
+/// The configuration of the timestepping.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestep {
+    pub mode: Timestepping,
+    /// The accuracy parameter η, see ETA.
+    pub eta: Scalar,
+    /// The maximum level, see MAX_LEVEL.
+    pub max_level: u32,
+}
+
+impl Default for Timestep {
+    fn default() -> Self {
+        Self { mode: Timestepping::default(), eta: ETA, max_level: MAX_LEVEL }
+    }
+}
+
+impl Timestep {
+    /// Returns the level k, so that delta / 2^k is the largest power-of-two fraction of delta
+    /// that satisfies the criterion dt = η * |a| / |ȧ|.
+    /// The derivative of the acceleration ȧ is estimated from two successive accelerations.
+    pub fn required_level(&self, acc: Vec2, acc_derivative: Vec2, delta: Scalar) -> u32 {
+        let dt = self.eta * acc.length() / acc_derivative.length();
+
+        // A vanishing derivative makes dt infinite or NaN.
+        if dt.is_nan() || dt >= delta { return 0 }
+
+        ((delta / dt).log2().ceil() as u32).min(self.max_level)
+    }
+}